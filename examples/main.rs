@@ -1,10 +1,14 @@
+use slide::batch::Batch;
+use slide::bucket::BucketPolicy;
 use slide::densified_wta_hash::DensifiedWtaHash;
+use slide::hasher::HasherConfig;
+use slide::layer::{NodeType, SamplingMode};
 use slide::network::{Case, LayerConfig, Network};
-use slide::layer::NodeType;
+use slide::optimizer::Adam;
 
 fn main() {
     let batch_size = 64;
-    let learning_rate = 0.01; //0.0001;
+    let optimizer = Adam::new(0.01); //0.0001;
     let input_size = 100;
     let case_per_rehash = 6400;
     let case_per_rebuild = 128000;
@@ -17,6 +21,11 @@ fn main() {
             l: 20,
             range_pow: 6,
             sparsity: 1.0,
+            sampling_mode: SamplingMode::Union,
+            bucket_size: 128,
+            bucket_policy: BucketPolicy::Fifo,
+            probes_per_table: 1,
+            hasher: HasherConfig::default(),
         },
         // LayerConfig {
         //     size: 1024,
@@ -25,6 +34,11 @@ fn main() {
         //     l: 20,
         //     range_pow: 8,
         //     sparsity: 0.1,
+        //     sampling_mode: SamplingMode::Union,
+        //     bucket_size: 128,
+        //     bucket_policy: BucketPolicy::Fifo,
+        //     probes_per_table: 1,
+        //     hasher: HasherConfig::default(),
         // },
         LayerConfig {
             size: 128,
@@ -33,6 +47,11 @@ fn main() {
             l: 30,
             range_pow: 10,
             sparsity: 0.8,
+            sampling_mode: SamplingMode::Union,
+            bucket_size: 128,
+            bucket_policy: BucketPolicy::Fifo,
+            probes_per_table: 1,
+            hasher: HasherConfig::default(),
         },
     ];
 
@@ -40,11 +59,11 @@ fn main() {
     for i in 0..1000 {
         let label = i % 10;
         let mut indices = vec![
-            rand::random::<usize>() % 5 * 10 + label,
-            rand::random::<usize>() % 5 * 10 + label,
-            rand::random::<usize>() % 5 * 10 + label,
-            rand::random::<usize>() % 5 * 10 + label,
-            rand::random::<usize>() % (5 * 10),
+            rand::random::<u32>() % 5 * 10 + label,
+            rand::random::<u32>() % 5 * 10 + label,
+            rand::random::<u32>() % 5 * 10 + label,
+            rand::random::<u32>() % 5 * 10 + label,
+            rand::random::<u32>() % (5 * 10),
         ];
         indices.sort();
         indices.dedup();
@@ -53,28 +72,33 @@ fn main() {
         cases.push(Case {
             indices,
             values,
-            labels: vec![label as u32],
+            labels: vec![label],
         });
     }
     dbg!(&cases[..3]);
 
     let start = std::time::Instant::now();
     let mut network =
-        Network::<DensifiedWtaHash>::new(batch_size, learning_rate, input_size, &layers);
+        Network::<DensifiedWtaHash>::new(batch_size, optimizer, input_size, &layers, 0);
     println!("network built elapsed: {:?}", start.elapsed());
 
     for i in 0..1000 {
         // dbg!("train...");
         let rehash = i % (case_per_rehash / batch_size) == case_per_rehash / batch_size - 1;
         let rebuild = i % (case_per_rebuild / batch_size) == case_per_rebuild / batch_size - 1;
-        network.train(&cases[i * batch_size % 900..], i, rehash, rebuild);
+        let start = i * batch_size % 900;
+        let batch: Batch = cases[start..start + batch_size]
+            .iter()
+            .map(Case::view)
+            .collect();
+        network.train(&batch, i, rehash, rebuild);
         // dbg!("train end");
 
         println!(
             "{:>5}: {:?}",
             i,
             (0..10)
-                .map(|i| network.predict(&cases[i]))
+                .map(|i| network.predict(cases[i].view()))
                 .collect::<Vec<_>>()
         );
     }
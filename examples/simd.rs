@@ -0,0 +1,78 @@
+//! Times the SIMD kernels against their scalar versions on an output layer
+//! the size of Amazon-670K's: 128 hidden nodes and 670091 labels.
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use slide::simd::{self, scalar};
+use std::time::{Duration, Instant};
+
+const HIDDEN_SIZE: usize = 128;
+const NUM_LABELS: usize = 670091;
+const BIN_SIZE: usize = 8;
+const NUM_BINS: usize = 6 * 50;
+
+/// Keeps the optimizer from discarding `x`, as `std::hint::black_box` does
+/// past Rust 1.51.
+fn black_box<T>(x: T) -> T {
+    // The volatile read has to assume `x` is observed.
+    let y = unsafe { std::ptr::read_volatile(&x) };
+    std::mem::forget(x);
+    y
+}
+
+/// Times the second run of `f`, the first one warming the caches up.
+fn time<F: FnMut() -> f32>(mut f: F) -> Duration {
+    black_box(f());
+    let start = Instant::now();
+    black_box(f());
+    start.elapsed()
+}
+
+fn report(name: &str, simd: Duration, scalar: Duration) {
+    println!(
+        "{:<14} simd {:>10.2?}  scalar {:>10.2?}  x{:.2}",
+        name,
+        simd,
+        scalar,
+        scalar.as_secs_f64() / simd.as_secs_f64()
+    );
+}
+
+fn main() {
+    let mut rng = StdRng::seed_from_u64(0);
+
+    // The output layer over the dense hidden layer, for one case.
+    let output: Vec<f32> = (0..NUM_LABELS * HIDDEN_SIZE)
+        .map(|_| rng.gen_range(0.0..0.01))
+        .collect();
+    let values: Vec<f32> = (0..HIDDEN_SIZE).map(|_| rng.gen()).collect();
+    let dense = |dot: fn(&[f32], &[f32]) -> f32| {
+        time(|| {
+            output
+                .chunks(HIDDEN_SIZE)
+                .map(|weights| dot(weights, &values))
+                .sum()
+        })
+    };
+    report("dense dot", dense(simd::dot), dense(scalar::dot));
+
+    // Hashing the output layer's nodes with WTA bins.
+    let bins: Vec<usize> = (0..NUM_BINS * BIN_SIZE)
+        .map(|_| rng.gen_range(0..HIDDEN_SIZE))
+        .collect();
+    let wta = |argmax: fn(&[f32], &[usize]) -> usize| {
+        time(|| {
+            let mut sum = 0;
+            for weights in output.chunks(HIDDEN_SIZE) {
+                for bin in bins.chunks(BIN_SIZE) {
+                    sum += argmax(weights, bin);
+                }
+            }
+            sum as f32
+        })
+    };
+    report(
+        "WTA argmax",
+        wta(simd::gather_argmax),
+        wta(scalar::gather_argmax),
+    );
+}
@@ -0,0 +1,110 @@
+use std::iter::FromIterator;
+
+/// A case borrowed from a `Batch` or a `Case`.
+#[derive(Clone, Copy, Debug)]
+pub struct CaseRef<'a> {
+    pub indices: &'a [u32],
+    pub values: &'a [f32],
+    pub labels: &'a [u32],
+}
+
+/// Cases stored in compressed sparse row form: the features of case `i` are
+/// `indices[offsets[i]..offsets[i + 1]]` with the matching `values`, and its
+/// labels are `labels[label_offsets[i]..label_offsets[i + 1]]`.
+#[derive(Clone, Debug)]
+pub struct Batch {
+    offsets: Vec<usize>,
+    indices: Vec<u32>,
+    values: Vec<f32>,
+    label_offsets: Vec<usize>,
+    labels: Vec<u32>,
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Batch {
+            offsets: vec![0],
+            indices: Vec::new(),
+            values: Vec::new(),
+            label_offsets: vec![0],
+            labels: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.offsets.truncate(1);
+        self.indices.clear();
+        self.values.clear();
+        self.label_offsets.truncate(1);
+        self.labels.clear();
+    }
+
+    pub fn push(&mut self, case: CaseRef<'_>) {
+        assert_eq!(case.indices.len(), case.values.len());
+        self.indices.extend_from_slice(case.indices);
+        self.values.extend_from_slice(case.values);
+        self.offsets.push(self.indices.len());
+        self.labels.extend_from_slice(case.labels);
+        self.label_offsets.push(self.labels.len());
+    }
+
+    pub fn get(&self, i: usize) -> CaseRef<'_> {
+        let features = self.offsets[i]..self.offsets[i + 1];
+        CaseRef {
+            indices: &self.indices[features.clone()],
+            values: &self.values[features],
+            labels: &self.labels[self.label_offsets[i]..self.label_offsets[i + 1]],
+        }
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = CaseRef<'_>> + ExactSizeIterator + '_ {
+        (0..self.len()).map(move |i| self.get(i))
+    }
+}
+
+impl Default for Batch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> FromIterator<CaseRef<'a>> for Batch {
+    fn from_iter<I: IntoIterator<Item = CaseRef<'a>>>(iter: I) -> Self {
+        let mut batch = Batch::new();
+        iter.into_iter().for_each(|case| batch.push(case));
+        batch
+    }
+}
+
+#[test]
+fn test() {
+    let mut batch = Batch::new();
+    batch.push(CaseRef {
+        indices: &[1, 5],
+        values: &[0.5, 1.0],
+        labels: &[3],
+    });
+    batch.push(CaseRef {
+        indices: &[],
+        values: &[],
+        labels: &[0, 2],
+    });
+    assert_eq!(batch.len(), 2);
+    assert_eq!(batch.get(0).indices, &[1, 5]);
+    assert_eq!(batch.get(0).labels, &[3]);
+    assert!(batch.get(1).values.is_empty());
+    assert_eq!(batch.get(1).labels, &[0, 2]);
+
+    let copy: Batch = batch.iter().rev().collect();
+    assert_eq!(copy.get(0).labels, &[0, 2]);
+    batch.clear();
+    assert!(batch.is_empty());
+}
@@ -1,57 +1,141 @@
-pub const BUCKET_SIZE: usize = 128;
-const FIFO: bool = true;
+use std::io::{self, Read, Write};
 
+use rand::Rng;
+
+use crate::checkpoint::*;
+
+/// What a full bucket does with a new id.
+#[derive(Clone, Copy)]
+pub enum BucketPolicy {
+    /// Overwrites the oldest id.
+    Fifo,
+    /// Keeps a uniform random sample of all the ids added.
+    Reservoir,
+    /// Drops the new id.
+    RejectWhenFull,
+}
+
+/// The ids of a bucket, whose capacity and policy are those of its table.
+#[derive(Default)]
 pub struct Bucket {
-    arr: [u32; BUCKET_SIZE],
+    arr: Vec<u32>,
     count: usize,
-    next_index: usize,
 }
 
 impl Bucket {
-    pub fn new() -> Self {
-        Self {
-            arr: [0; BUCKET_SIZE],
-            count: 0,
-            next_index: 0,
-        }
-    }
-
     pub fn clear(&mut self) {
+        self.arr.clear();
         self.count = 0;
-        self.next_index = 0;
     }
 
     pub fn get_size(&self) -> usize {
         self.count
     }
 
-    pub fn add(&mut self, id: u32) -> usize {
+    /// Adds `id`, returning the slot it went to or `usize::MAX` if it was
+    /// not kept. `rng` is only used by the reservoir policy.
+    pub fn add<R: Rng>(
+        &mut self,
+        id: u32,
+        capacity: usize,
+        policy: BucketPolicy,
+        rng: &mut R,
+    ) -> usize {
         self.count += 1;
-        if FIFO {
-            // FIFO
-            let index = (self.count - 1) & (BUCKET_SIZE - 1);
-            self.arr[index] = id;
-            index
-        } else {
-            // Reservoir Sampling
-            if self.next_index == BUCKET_SIZE {
-                if rand::random::<usize>() % self.count == 1 {
-                    let index = rand::random::<usize>() % BUCKET_SIZE;
+        if self.arr.len() < capacity {
+            self.arr.push(id);
+            return self.arr.len() - 1;
+        }
+        match policy {
+            BucketPolicy::Fifo => {
+                let index = (self.count - 1) % capacity;
+                self.arr[index] = id;
+                index
+            }
+            BucketPolicy::Reservoir => {
+                // Algorithm R: the `count`th id is kept with probability
+                // `capacity / count`, replacing a uniformly chosen slot.
+                let index = rng.gen_range(0..self.count);
+                if index < capacity {
                     self.arr[index] = id;
                     index
                 } else {
                     usize::MAX
                 }
-            } else {
-                let index = self.next_index;
-                self.next_index += 1;
-                self.arr[index] = id;
-                index
             }
+            BucketPolicy::RejectWhenFull => usize::MAX,
         }
     }
 
     pub fn get_all(&self) -> &[u32] {
-        &self.arr[..self.count.min(BUCKET_SIZE)]
+        &self.arr
+    }
+
+    /// Writes the number of ids added and the ids kept.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_u32(w, self.count as u32)?;
+        self.arr.iter().try_for_each(|&id| write_u32(w, id))
+    }
+
+    /// Reads the ids written by `write_to` in place of the current ones,
+    /// checking they are below `number_of_ids`.
+    pub fn read_from<R: Read>(
+        &mut self,
+        r: &mut R,
+        capacity: usize,
+        number_of_ids: usize,
+    ) -> io::Result<()> {
+        self.count = read_u32(r)? as usize;
+        let len = self.count.min(capacity);
+        self.arr.clear();
+        for _ in 0..len {
+            let id = read_u32(r)?;
+            if id as usize >= number_of_ids {
+                return Err(invalid_data("corrupted hash table"));
+            }
+            self.arr.push(id);
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test() {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    let mut rng = StdRng::seed_from_u64(42);
+
+    let mut bucket = Bucket::default();
+    for id in 0..6 {
+        bucket.add(id, 4, BucketPolicy::Fifo, &mut rng);
+    }
+    assert_eq!(bucket.get_all(), &[4, 5, 2, 3]);
+    assert_eq!(bucket.get_size(), 6);
+
+    let mut bucket = Bucket::default();
+    for id in 0..6 {
+        bucket.add(id, 4, BucketPolicy::RejectWhenFull, &mut rng);
+    }
+    assert_eq!(bucket.get_all(), &[0, 1, 2, 3]);
+
+    // Every id added to a reservoir is retained with the same probability.
+    const TRIALS: usize = 2000;
+    const IDS: u32 = 1000;
+    const CAPACITY: usize = 10;
+    let mut retained = vec![0; 10];
+    let mut bucket = Bucket::default();
+    for _ in 0..TRIALS {
+        bucket.clear();
+        for id in 0..IDS {
+            bucket.add(id, CAPACITY, BucketPolicy::Reservoir, &mut rng);
+        }
+        assert_eq!(bucket.get_all().len(), CAPACITY);
+        for &id in bucket.get_all() {
+            retained[(id * 10 / IDS) as usize] += 1;
+        }
+    }
+    let expected = (TRIALS * CAPACITY / 10) as f32;
+    for count in retained {
+        assert!((count as f32 - expected).abs() < expected * 0.1);
     }
 }
@@ -0,0 +1,83 @@
+//! Little-endian primitives of the checkpoint format written by
+//! `Network::save`.
+
+use std::io::{self, Read, Write};
+
+pub const MAGIC: &[u8; 8] = b"SLIDENET";
+pub const FORMAT_VERSION: u32 = 13;
+
+pub fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+pub fn write_u8<W: Write>(w: &mut W, value: u8) -> io::Result<()> {
+    w.write_all(&[value])
+}
+
+pub fn write_u32<W: Write>(w: &mut W, value: u32) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub fn write_u64<W: Write>(w: &mut W, value: u64) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub fn write_usize<W: Write>(w: &mut W, value: usize) -> io::Result<()> {
+    write_u64(w, value as u64)
+}
+
+pub fn write_f32<W: Write>(w: &mut W, value: f32) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub fn write_usizes<W: Write>(w: &mut W, values: &[usize]) -> io::Result<()> {
+    write_usize(w, values.len())?;
+    values.iter().try_for_each(|&v| write_usize(w, v))
+}
+
+pub fn write_u32s<W: Write>(w: &mut W, values: &[u32]) -> io::Result<()> {
+    write_usize(w, values.len())?;
+    values.iter().try_for_each(|&v| write_u32(w, v))
+}
+
+pub fn read_u8<R: Read>(r: &mut R) -> io::Result<u8> {
+    let mut bytes = [0; 1];
+    r.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+pub fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+pub fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    r.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub fn read_usize<R: Read>(r: &mut R) -> io::Result<usize> {
+    let value = read_u64(r)?;
+    if value > usize::MAX as u64 {
+        return Err(invalid_data("value does not fit in usize"));
+    }
+    Ok(value as usize)
+}
+
+pub fn read_f32<R: Read>(r: &mut R) -> io::Result<f32> {
+    let mut bytes = [0; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(f32::from_le_bytes(bytes))
+}
+
+pub fn read_usizes<R: Read>(r: &mut R) -> io::Result<Vec<usize>> {
+    let len = read_usize(r)?;
+    (0..len).map(|_| read_usize(r)).collect()
+}
+
+pub fn read_u32s<R: Read>(r: &mut R) -> io::Result<Vec<u32>> {
+    let len = read_usize(r)?;
+    (0..len).map(|_| read_u32(r)).collect()
+}
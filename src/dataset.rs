@@ -0,0 +1,385 @@
+//! Datasets in the Extreme Classification Repository text format:
+//!
+//! ```text
+//! num_cases num_features num_labels
+//! label,label,... index:value index:value ...
+//! ```
+
+use std::{
+    error::Error,
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::Path,
+};
+
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+use crate::{batch::Batch, network::Case};
+
+/// The counts on the first line of a dataset file.
+#[derive(Clone, Copy, Debug)]
+pub struct Header {
+    pub num_cases: usize,
+    pub num_features: usize,
+    pub num_labels: usize,
+}
+
+#[derive(Debug)]
+pub enum ParseErrorKind {
+    Io(io::Error),
+    InvalidHeader,
+    InvalidLabel(String),
+    InvalidFeature(String),
+    /// The input ended after another number of cases than the header's.
+    WrongNumberOfCases {
+        expected: usize,
+        found: usize,
+    },
+}
+
+#[derive(Debug)]
+pub struct ParseError {
+    /// 1-based, the header being line 1.
+    pub line: usize,
+    pub kind: ParseErrorKind,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind {
+            ParseErrorKind::Io(error) => write!(f, "{}", error),
+            ParseErrorKind::InvalidHeader => write!(f, "invalid header"),
+            ParseErrorKind::InvalidLabel(label) => write!(f, "invalid label {:?}", label),
+            ParseErrorKind::InvalidFeature(feature) => {
+                write!(f, "invalid feature {:?}", feature)
+            }
+            ParseErrorKind::WrongNumberOfCases { expected, found } => {
+                write!(f, "expected {} cases, found {}", expected, found)
+            }
+        }
+    }
+}
+
+impl Error for ParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match &self.kind {
+            ParseErrorKind::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Streams the cases of a dataset file, checking them against its header.
+pub struct XmcReader<R> {
+    lines: io::Lines<R>,
+    line: usize,
+    header: Header,
+    // The number of cases read, `None` once the end has been reached.
+    cases: Option<usize>,
+}
+
+impl XmcReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, ParseError> {
+        let file = File::open(path).map_err(|error| ParseError {
+            line: 0,
+            kind: ParseErrorKind::Io(error),
+        })?;
+        Self::new(BufReader::new(file))
+    }
+}
+
+impl<R: BufRead> XmcReader<R> {
+    /// Reads the header, leaving the cases to the iterator.
+    pub fn new(reader: R) -> Result<Self, ParseError> {
+        let mut reader = XmcReader {
+            lines: reader.lines(),
+            line: 0,
+            header: Header {
+                num_cases: 0,
+                num_features: 0,
+                num_labels: 0,
+            },
+            cases: Some(0),
+        };
+        let line = reader.next_line().unwrap_or(Ok(String::new()))?;
+        let counts = line
+            .split_whitespace()
+            .map(|s| s.parse())
+            .collect::<Result<Vec<usize>, _>>();
+        reader.header = match counts.as_deref() {
+            Ok(&[num_cases, num_features, num_labels]) => Header {
+                num_cases,
+                num_features,
+                num_labels,
+            },
+            _ => return Err(reader.error(ParseErrorKind::InvalidHeader)),
+        };
+        Ok(reader)
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Groups the cases into batches of `batch_size`, the last one possibly
+    /// smaller.
+    pub fn batches(self, batch_size: usize) -> Batches<R> {
+        Batches {
+            reader: self,
+            batch_size,
+        }
+    }
+
+    fn next_line(&mut self) -> Option<Result<String, ParseError>> {
+        let line = self.lines.next()?;
+        self.line += 1;
+        Some(line.map_err(|error| self.error(ParseErrorKind::Io(error))))
+    }
+
+    fn error(&self, kind: ParseErrorKind) -> ParseError {
+        ParseError {
+            line: self.line,
+            kind,
+        }
+    }
+
+    fn parse_case(&self, line: &str) -> Result<Case, ParseError> {
+        // An unlabeled case starts with the separating space.
+        let (labels, features) = match line.find(' ') {
+            Some(i) => (&line[..i], &line[i + 1..]),
+            None => (line, ""),
+        };
+
+        let mut case = Case {
+            indices: Vec::new(),
+            values: Vec::new(),
+            labels: Vec::new(),
+        };
+        for label in labels.split(',').filter(|s| !s.is_empty()) {
+            match label.parse::<u32>() {
+                Ok(l) if (l as usize) < self.header.num_labels => case.labels.push(l),
+                _ => return Err(self.error(ParseErrorKind::InvalidLabel(label.to_owned()))),
+            }
+        }
+        for feature in features.split_whitespace() {
+            let mut parts = feature.splitn(2, ':');
+            let parsed = match (parts.next(), parts.next()) {
+                (Some(index), Some(value)) => index.parse().ok().zip(value.parse().ok()),
+                _ => None,
+            };
+            match parsed {
+                Some((index, value)) if (index as usize) < self.header.num_features => {
+                    case.indices.push(index);
+                    case.values.push(value);
+                }
+                _ => {
+                    return Err(self.error(ParseErrorKind::InvalidFeature(feature.to_owned())));
+                }
+            }
+        }
+        Ok(case)
+    }
+}
+
+impl<R: BufRead> Iterator for XmcReader<R> {
+    type Item = Result<Case, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.next_line() {
+                Some(Ok(line)) => line,
+                Some(Err(error)) => return Some(Err(error)),
+                None => {
+                    let found = self.cases.take()?;
+                    let expected = self.header.num_cases;
+                    if found == expected {
+                        return None;
+                    }
+                    let kind = ParseErrorKind::WrongNumberOfCases { expected, found };
+                    return Some(Err(self.error(kind)));
+                }
+            };
+            let line = line.trim_end();
+            if !line.is_empty() {
+                if let Some(cases) = &mut self.cases {
+                    *cases += 1;
+                }
+                return Some(self.parse_case(line));
+            }
+        }
+    }
+}
+
+pub struct Batches<R> {
+    reader: XmcReader<R>,
+    batch_size: usize,
+}
+
+impl<R: BufRead> Iterator for Batches<R> {
+    type Item = Result<Batch, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut cases = Batch::new();
+        for case in self.reader.by_ref().take(self.batch_size) {
+            match case {
+                Ok(case) => cases.push(case.view()),
+                Err(error) => return Some(Err(error)),
+            }
+        }
+        if cases.is_empty() {
+            None
+        } else {
+            Some(Ok(cases))
+        }
+    }
+}
+
+/// A whole dataset held in memory, to be iterated over for several epochs.
+pub struct Dataset {
+    header: Header,
+    cases: Batch,
+    // The order of the cases in `batches`.
+    order: Vec<u32>,
+}
+
+impl Dataset {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ParseError> {
+        Self::from_reader(XmcReader::open(path)?)
+    }
+
+    pub fn from_reader<R: BufRead>(reader: XmcReader<R>) -> Result<Self, ParseError> {
+        let header = *reader.header();
+        let mut cases = Batch::new();
+        for case in reader {
+            cases.push(case?.view());
+        }
+        let order = (0..cases.len() as u32).collect();
+        Ok(Dataset {
+            header,
+            cases,
+            order,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn len(&self) -> usize {
+        self.cases.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cases.is_empty()
+    }
+
+    /// The cases in file order.
+    pub fn cases(&self) -> &Batch {
+        &self.cases
+    }
+
+    /// Number of cases of every label.
+    pub fn label_counts(&self) -> Vec<usize> {
+        let mut counts = vec![0; self.header.num_labels];
+        for case in self.cases.iter() {
+            for &label in case.labels {
+                counts[label as usize] += 1;
+            }
+        }
+        counts
+    }
+
+    /// Reorders the cases of `batches` randomly, the same way for the same
+    /// `seed` whatever the earlier shuffles. Meant to be called at the start
+    /// of every epoch with a seed derived from it.
+    pub fn shuffle(&mut self, seed: u64) {
+        for (i, id) in self.order.iter_mut().enumerate() {
+            *id = i as u32;
+        }
+        self.order.shuffle(&mut StdRng::seed_from_u64(seed));
+    }
+
+    pub fn num_batches(&self, batch_size: usize) -> usize {
+        (self.cases.len() + batch_size - 1) / batch_size
+    }
+
+    /// The cases in batches of `batch_size`, the last one possibly smaller.
+    pub fn batches(&self, batch_size: usize) -> impl Iterator<Item = Batch> + '_ {
+        self.order
+            .chunks(batch_size)
+            .map(move |ids| ids.iter().map(|&i| self.cases.get(i as usize)).collect())
+    }
+}
+
+#[test]
+fn test() {
+    let data = "3 10 5\n1,4 0:0.5 9:1\n 2:0.25\n\n3 \n";
+    let reader = XmcReader::new(data.as_bytes()).unwrap();
+    assert_eq!(reader.header().num_features, 10);
+    let batches: Vec<_> = reader.batches(2).map(Result::unwrap).collect();
+    assert_eq!(batches.len(), 2);
+    assert_eq!(batches[0].get(0).labels, &[1, 4]);
+    assert_eq!(batches[0].get(0).indices, &[0, 9]);
+    assert_eq!(batches[0].get(0).values, &[0.5, 1.0]);
+    assert!(batches[0].get(1).labels.is_empty());
+    assert_eq!(batches[0].get(1).indices, &[2]);
+    assert_eq!(batches[1].get(0).labels, &[3]);
+    assert!(batches[1].get(0).indices.is_empty());
+
+    let mut reader = XmcReader::new("1 10 5\n1 0:0.5 10:1\n".as_bytes()).unwrap();
+    let error = reader.next().unwrap().unwrap_err();
+    assert_eq!(error.line, 2);
+    assert!(matches!(error.kind, ParseErrorKind::InvalidFeature(ref f) if f == "10:1"));
+
+    let mut reader = XmcReader::new("1 10 5\n1,x 0:0.5\n".as_bytes()).unwrap();
+    let error = reader.next().unwrap().unwrap_err();
+    assert_eq!(error.to_string(), "line 2: invalid label \"x\"");
+
+    assert!(XmcReader::new("1 10\n".as_bytes()).is_err());
+
+    // A truncated file.
+    let mut reader = XmcReader::new("3 10 5\n1 0:0.5\n2 1:1\n".as_bytes()).unwrap();
+    assert!(reader.next().unwrap().is_ok());
+    assert!(reader.next().unwrap().is_ok());
+    let error = reader.next().unwrap().unwrap_err();
+    assert_eq!(error.to_string(), "line 3: expected 3 cases, found 2");
+    assert!(reader.next().is_none());
+}
+
+#[test]
+fn test_dataset() {
+    let data = "5 4 3\n0 0:1\n1 1:1\n2 2:1\n0,1 3:1\n0 0:2\n";
+    let mut dataset = Dataset::from_reader(XmcReader::new(data.as_bytes()).unwrap()).unwrap();
+    assert_eq!(dataset.len(), 5);
+    assert_eq!(dataset.label_counts(), vec![3, 2, 1]);
+    assert_eq!(dataset.num_batches(2), 3);
+    assert_eq!(
+        dataset.batches(2).map(|b| b.len()).collect::<Vec<_>>(),
+        vec![2, 2, 1]
+    );
+
+    let order = |dataset: &Dataset| -> Vec<_> {
+        let mut values = Vec::new();
+        for batch in dataset.batches(2) {
+            values.extend(batch.iter().map(|c| c.values[0] + c.indices[0] as f32));
+        }
+        values
+    };
+    let original = order(&dataset);
+    dataset.shuffle(3);
+    let shuffled = order(&dataset);
+    let mut again = Dataset::from_reader(XmcReader::new(data.as_bytes()).unwrap()).unwrap();
+    again.shuffle(3);
+    assert_eq!(shuffled, order(&again));
+    again.shuffle(4);
+    again.shuffle(3);
+    assert_eq!(shuffled, order(&again));
+
+    let mut sorted = shuffled;
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let mut original = original;
+    original.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    assert_eq!(sorted, original);
+}
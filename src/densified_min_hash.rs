@@ -1,3 +1,173 @@
+use std::io::{self, Read, Write};
+
+use rand::rngs::StdRng;
+
+use crate::{
+    checkpoint::*,
+    hasher::{Hasher, HasherConfig},
+};
+
+/// Number of largest weights taken as the set when hashing a dense vector.
+const TOPK: usize = 128;
+
+/// Densified one permutation MinHash for Jaccard similarity.
+///
+/// The dimensions are randomly permuted once and cut into `size` bins; the
+/// hash of a bin is the smallest permuted position of the set falling in it.
+/// Empty bins borrow the hash of a non-empty bin chosen by a random probing
+/// sequence, so every hash is defined as long as the set is not empty.
 pub struct DensifiedMinHash {
+    size: usize,
+    bin_size: usize,
+    permutation: Vec<u32>,
+    rand_hash: u64,
+}
+
+impl Hasher for DensifiedMinHash {
+    const TAG: u8 = 2;
+
+    fn new(size: usize, number_of_bits: usize, _config: &HasherConfig, rng: &mut StdRng) -> Self {
+        use rand::{seq::SliceRandom, Rng};
+
+        let mut permutation: Vec<u32> = (0..number_of_bits as u32).collect();
+        permutation.shuffle(rng);
+
+        DensifiedMinHash {
+            size,
+            bin_size: (number_of_bits + size - 1) / size,
+            permutation,
+            rand_hash: rng.gen::<u64>() | 1,
+        }
+    }
+
+    fn hash(&self, weights: &[f32]) -> Vec<usize> {
+        // A dense vector is reduced to the set of its largest nonzero entries.
+        let mut indices: Vec<usize> = (0..weights.len()).filter(|&i| weights[i] != 0.0).collect();
+        if indices.len() > TOPK {
+            indices
+                .select_nth_unstable_by(TOPK, |&a, &b| crate::total_cmp(&weights[b], &weights[a]));
+            indices.truncate(TOPK);
+        }
+        self.hash_set(indices.into_iter())
+    }
+
+    fn hash_sparse(&self, weights: &[f32], indices: &[usize]) -> Vec<usize> {
+        self.hash_set(
+            indices
+                .iter()
+                .zip(weights)
+                .filter(|(_, &w)| w != 0.0)
+                .map(|(&i, _)| i),
+        )
+    }
+
+    fn hashes_to_indices(
+        &self,
+        hashes: &[usize],
+        k: usize,
+        l: usize,
+        range_pow: usize,
+    ) -> Vec<usize> {
+        (0..l)
+            .map(|i| {
+                let mut index = 0u64;
+                for j in 0..k {
+                    let h = hashes[k * i + j] as u64;
+                    index = (index ^ h).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+                    index ^= index >> 29;
+                }
+                index as usize & ((1 << range_pow) - 1)
+            })
+            .collect()
+    }
+
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_usize(w, self.size)?;
+        write_usize(w, self.bin_size)?;
+        write_u32s(w, &self.permutation)?;
+        write_u64(w, self.rand_hash)
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        let hasher = DensifiedMinHash {
+            size: read_usize(r)?,
+            bin_size: read_usize(r)?,
+            permutation: read_u32s(r)?,
+            rand_hash: read_u64(r)?,
+        };
+        if hasher.size * hasher.bin_size < hasher.permutation.len() {
+            return Err(invalid_data("corrupted hasher"));
+        }
+        Ok(hasher)
+    }
+}
+
+impl DensifiedMinHash {
+    fn hash_set(&self, indices: impl Iterator<Item = usize>) -> Vec<usize> {
+        let mut hashes = vec![usize::MAX; self.size];
+        for i in indices {
+            let position = self.permutation[i] as usize;
+            let bin = position / self.bin_size;
+            hashes[bin] = hashes[bin].min(position);
+        }
+        self.densify(hashes)
+    }
+
+    fn rand_double_hash(&self, binid: usize, count: usize) -> usize {
+        let tohash = (((binid as u64) << 32) | count as u64).wrapping_mul(self.rand_hash);
+        ((tohash >> 32) as usize) % self.size
+    }
+
+    fn densify(&self, hashes: Vec<usize>) -> Vec<usize> {
+        if hashes.iter().all(|&h| h == usize::MAX) {
+            // The empty set: every bin gets a value no real element produces.
+            return vec![self.permutation.len(); self.size];
+        }
+        (0..self.size)
+            .map(|i| {
+                let mut hash = hashes[i];
+                let mut count = 0;
+                while hash == usize::MAX {
+                    count += 1;
+                    hash = if count <= 100 {
+                        hashes[self.rand_double_hash(i, count)]
+                    } else {
+                        // Unlucky probing sequence, fall back to a linear scan.
+                        hashes[(i + count - 100) % self.size]
+                    };
+                }
+                hash
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test() {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let hash = DensifiedMinHash::new(64, 200, &HasherConfig::default(), &mut rng);
+    let mut dense = vec![0.0; 200];
+    for &i in &[3, 17, 42, 199] {
+        dense[i] = 1.0;
+    }
+    assert_eq!(
+        hash.hash(&dense),
+        hash.hash_sparse(&[1.0, 1.0, 1.0, 1.0], &[3, 17, 42, 199])
+    );
+    assert_eq!(hash.hash_sparse(&[], &[]), vec![200; 64]);
 
-}
\ No newline at end of file
+    // Sets with Jaccard similarity 1/3 collide on about a third of the hashes.
+    let hash = DensifiedMinHash::new(512, 1000, &HasherConfig::default(), &mut rng);
+    let a: Vec<usize> = (0..200).collect();
+    let b: Vec<usize> = (100..300).collect();
+    let ones = vec![1.0; 200];
+    let collisions = hash
+        .hash_sparse(&ones, &a)
+        .iter()
+        .zip(hash.hash_sparse(&ones, &b))
+        .filter(|(x, y)| **x == *y)
+        .count();
+    assert!((collisions as f32 / 512.0 - 1.0 / 3.0).abs() < 0.1);
+}
@@ -1,142 +1,441 @@
-use crate::hasher::Hasher;
+//! Densified winner-take-all hashing, laid out as in the original SLIDE:
+//! `permute` random permutations of the inputs are concatenated and cut into
+//! bins of `bin_size`, the hash of a bin being the position of its largest
+//! input. The bins none of a sparse input fell into are filled as set by
+//! `Densification`.
 
-const BIN_SIZE: usize = 8;
+use std::io::{self, Read, Write};
+
+use rand::rngs::StdRng;
+
+use crate::{
+    checkpoint::*,
+    hasher::{pack_hashes, Densification, Hasher, HasherConfig, Probe},
+    simd,
+};
+
+// The hash of the bins no input fell into, before densification.
+const EMPTY: usize = usize::MAX;
 
 pub struct DensifiedWtaHash {
     size: usize,
-    indices: Vec<usize>,
+    number_of_bits: usize,
+    bin_size: usize,
+    permute: usize,
+    // Input `i` goes in permutation `p` to the bin `bin_ids[p * number_of_bits + i]`,
+    // at the position `positions[p * number_of_bits + i]`.
+    bin_ids: Vec<u32>,
+    positions: Vec<u32>,
+    // The inverse for dense inputs: the inputs of bin `b` are
+    // `bin_inputs[b * bin_size..(b + 1) * bin_size]` in the order `bins`
+    // visits them, at the matching `bin_positions`.
+    bin_inputs: Vec<usize>,
+    bin_positions: Vec<u32>,
+    rand_hash: u32,
+    log_size: u32,
+    densification: Densification,
 }
 
 impl Hasher for DensifiedWtaHash {
-    fn new(size: usize, number_of_bits: usize) -> Self {
-        use rand::seq::SliceRandom;
+    const TAG: u8 = 1;
 
-        assert!(BIN_SIZE <= number_of_bits);
-
-        let mut rng = rand::thread_rng();
+    fn new(size: usize, number_of_bits: usize, config: &HasherConfig, rng: &mut StdRng) -> Self {
+        use rand::{seq::SliceRandom, Rng};
 
+        let bin_size = config.bin_size;
+        assert!(0 < bin_size);
+        let permute = (size * bin_size + number_of_bits - 1) / number_of_bits;
         let mut n_array: Vec<usize> = (0..number_of_bits).collect();
-        let mut indices = vec![0; size * BIN_SIZE];
-
-        for i in 0..size {
-            n_array.shuffle(&mut rng);
-            for j in 0..BIN_SIZE {
-                indices[i * BIN_SIZE + j] = n_array[j];
+        let mut bin_ids = vec![0; number_of_bits * permute];
+        let mut positions = vec![0; number_of_bits * permute];
+        for p in 0..permute {
+            n_array.shuffle(rng);
+            for (j, &n) in n_array.iter().enumerate() {
+                let slot = p * number_of_bits + j;
+                bin_ids[p * number_of_bits + n] = (slot / bin_size) as u32;
+                positions[p * number_of_bits + n] = (slot % bin_size) as u32;
             }
         }
 
-        DensifiedWtaHash { size, indices }
+        let (bin_inputs, bin_positions) =
+            bin_inputs(size, number_of_bits, bin_size, &bin_ids, &positions).unwrap();
+        DensifiedWtaHash {
+            size,
+            number_of_bits,
+            bin_size,
+            permute,
+            bin_ids,
+            positions,
+            bin_inputs,
+            bin_positions,
+            rand_hash: rng.gen_range(1..=i32::MAX as u32) | 1,
+            log_size: 63 - (size.max(1) as u64).leading_zeros(),
+            densification: config.densification,
+        }
     }
 
     fn hash(&self, weights: &[f32]) -> Vec<usize> {
-        // binsize is the number of times the range is larger than the total number of hashes we need.
-        let mut hashes = vec![0; self.size];
-
-        for i in 0..self.size {
-            let mut w = f32::MIN;
-            for j in 0..BIN_SIZE {
-                let k = self.indices[i * BIN_SIZE + j];
-                if w < weights[k] {
-                    w = weights[k];
-                    hashes[i] = j;
+        // Every bin is full, there is nothing to densify.
+        self.bin_inputs
+            .chunks(self.bin_size)
+            .zip(self.bin_positions.chunks(self.bin_size))
+            .map(|(inputs, positions)| positions[simd::gather_argmax(weights, inputs)] as usize)
+            .collect()
+    }
+
+    fn hash_sparse(&self, weights: &[f32], indices: &[usize]) -> Vec<usize> {
+        let inputs = indices.iter().cloned().zip(weights.iter().cloned());
+        let hashes = self.bins(inputs, |bin, _, _| bin.hash);
+        self.densify(hashes, |hash, offset| (hash + offset) % self.bin_size, 0)
+    }
+
+    fn probe_sparse(&self, weights: &[f32], indices: &[usize]) -> Vec<Probe> {
+        let inputs = indices.iter().cloned().zip(weights.iter().cloned());
+        let probes = self.bins(inputs, |bin, best, second| Probe {
+            margin: if bin.runner_up == EMPTY {
+                f32::INFINITY
+            } else {
+                best - second
+            },
+            runner_up: if bin.runner_up == EMPTY {
+                bin.hash
+            } else {
+                bin.runner_up
+            },
+            ..bin
+        });
+        self.densify(
+            probes,
+            |probe, offset| Probe {
+                hash: (probe.hash + offset) % self.bin_size,
+                runner_up: (probe.runner_up + offset) % self.bin_size,
+                margin: probe.margin,
+            },
+            Probe::exact(0),
+        )
+    }
+
+    fn hashes_to_indices(
+        &self,
+        hashes: &[usize],
+        k: usize,
+        l: usize,
+        range_pow: usize,
+    ) -> Vec<usize> {
+        pack_hashes(hashes, k, l, range_pow, self.bin_size)
+    }
+
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_usize(w, self.size)?;
+        write_usize(w, self.number_of_bits)?;
+        write_usize(w, self.bin_size)?;
+        write_usize(w, self.permute)?;
+        write_u32s(w, &self.bin_ids)?;
+        write_u32s(w, &self.positions)?;
+        write_u32(w, self.rand_hash)?;
+        write_u8(w, self.densification as u8)
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        let size = read_usize(r)?;
+        let number_of_bits = read_usize(r)?;
+        let bin_size = read_usize(r)?;
+        let permute = read_usize(r)?;
+        let bin_ids = read_u32s(r)?;
+        let positions = read_u32s(r)?;
+        let rand_hash = read_u32(r)?;
+        let densification = Densification::from_u8(read_u8(r)?)?;
+        let len = number_of_bits * permute;
+        if bin_size == 0 || bin_ids.len() != len || positions.len() != len {
+            return Err(invalid_data("corrupted hasher"));
+        }
+        let (bin_inputs, bin_positions) =
+            bin_inputs(size, number_of_bits, bin_size, &bin_ids, &positions)
+                .ok_or_else(|| invalid_data("corrupted hasher"))?;
+        Ok(DensifiedWtaHash {
+            size,
+            number_of_bits,
+            bin_size,
+            permute,
+            bin_ids,
+            positions,
+            bin_inputs,
+            bin_positions,
+            rand_hash,
+            log_size: 63 - (size.max(1) as u64).leading_zeros(),
+            densification,
+        })
+    }
+}
+
+impl DensifiedWtaHash {
+    /// Runs the inputs through every permutation and returns `f(bin, best,
+    /// second)` for every bin, where `bin.hash` is the position of the first
+    /// largest value `best` and `bin.runner_up` that of the next largest
+    /// `second`, both `EMPTY` if there are no such values.
+    fn bins<T>(
+        &self,
+        inputs: impl Iterator<Item = (usize, f32)> + Clone,
+        f: impl Fn(Probe, f32, f32) -> T,
+    ) -> Vec<T> {
+        let empty = Probe {
+            hash: EMPTY,
+            runner_up: EMPTY,
+            margin: 0.0,
+        };
+        let mut bins = vec![empty; self.size];
+        let mut values = vec![(i32::MIN as f32, i32::MIN as f32); self.size];
+        for p in 0..self.permute {
+            let offset = p * self.number_of_bits;
+            for (i, weight) in inputs.clone() {
+                let slot = offset + i;
+                let bin = self.bin_ids[slot] as usize;
+                if bin >= self.size {
+                    continue;
+                }
+                let (best, second) = &mut values[bin];
+                if *best < weight {
+                    if bins[bin].hash != EMPTY {
+                        bins[bin].runner_up = bins[bin].hash;
+                        *second = *best;
+                    }
+                    bins[bin].hash = self.positions[slot] as usize;
+                    *best = weight;
+                } else if bins[bin].runner_up == EMPTY || *second < weight {
+                    bins[bin].runner_up = self.positions[slot] as usize;
+                    *second = weight;
                 }
             }
         }
+        bins.into_iter()
+            .zip(values)
+            .map(|(bin, (best, second))| f(bin, best, second))
+            .collect()
+    }
 
-        self.densify(hashes)
+    /// `getRandDoubleHash` of the original, in its 32-bit arithmetic.
+    fn rand_double_hash(&self, bin: usize, count: usize) -> usize {
+        let to_hash = ((bin as u32 + 1) << 10).wrapping_add(count as u32);
+        (self.rand_hash.wrapping_mul(to_hash) << 3)
+            .checked_shr(32 - self.log_size)
+            .unwrap_or(0) as usize
     }
 
-    fn hash_sparse(&self, weights: &[f32], indices: &[usize]) -> Vec<usize> {
-        let mut hashes = vec![0; self.size];
-
-        for i in 0..self.size {
-            let mut w = f32::MIN;
-            for j in 0..BIN_SIZE {
-                let k = self.indices[i * BIN_SIZE + j];
-                let weight = indices
-                    .iter()
-                    .position(|i| k == *i)
-                    .map(|i| weights[i])
-                    .unwrap_or_default();
-                if w < weight {
-                    w = weight;
-                    hashes[i] = j;
-                }
+    /// Fills the empty bins, `offset(bin, attempt * 1234)` giving the value
+    /// borrowed from `bin` by the probing densification and `failure` that
+    /// of the bins no nonempty one is found for.
+    fn densify<T: Copy + HasHash>(
+        &self,
+        bins: Vec<T>,
+        offset: impl Fn(T, usize) -> T,
+        failure: T,
+    ) -> Vec<T> {
+        match self.densification {
+            Densification::Original => (0..self.size)
+                .map(|i| {
+                    let mut bin = bins[i];
+                    let mut count = 0;
+                    while bin.hash() == EMPTY {
+                        count += 1;
+                        bin = bins[self.rand_double_hash(i, count).min(self.size - 1)];
+                        if count > 100 {
+                            break;
+                        }
+                    }
+                    if bin.hash() == EMPTY {
+                        failure
+                    } else {
+                        bin
+                    }
+                })
+                .collect(),
+            Densification::Probing => {
+                const C: usize = 1234;
+                let h = |i: usize, a: usize| (i * 1234 + a * 567) % self.size;
+                (0..self.size)
+                    .map(|i| {
+                        let mut next = i;
+                        for attempt in 0..100 {
+                            if bins[next].hash() != EMPTY {
+                                return offset(bins[next], attempt * C);
+                            }
+                            next = h(i, attempt + 1);
+                        }
+                        failure
+                    })
+                    .collect()
             }
         }
+    }
+}
 
-        self.densify(hashes)
+/// Lists the inputs of every bin and their positions, in the order of the
+/// permutations then of the inputs; `None` unless every bin has `bin_size`
+/// of them.
+fn bin_inputs(
+    size: usize,
+    number_of_bits: usize,
+    bin_size: usize,
+    bin_ids: &[u32],
+    positions: &[u32],
+) -> Option<(Vec<usize>, Vec<u32>)> {
+    let mut counts = vec![0; size];
+    let mut inputs = vec![0; size * bin_size];
+    let mut bin_positions = vec![0; size * bin_size];
+    for (slot, (&bin, &position)) in bin_ids.iter().zip(positions).enumerate() {
+        let bin = bin as usize;
+        if bin >= size {
+            continue;
+        }
+        if counts[bin] == bin_size {
+            return None;
+        }
+        inputs[bin * bin_size + counts[bin]] = slot % number_of_bits;
+        bin_positions[bin * bin_size + counts[bin]] = position;
+        counts[bin] += 1;
+    }
+    if counts.iter().all(|&count| count == bin_size) {
+        Some((inputs, bin_positions))
+    } else {
+        None
     }
+}
 
-    fn hashes_to_indices(hashes: &[usize], k: usize, l: usize, range_pow: usize) -> Vec<usize> {
-        let bin_size_log2 = (BIN_SIZE as f32).log2() as usize;
-        (0..l)
-            .map(|i| {
-                let mut index = 0;
-                for j in 0..k {
-                    let h = hashes[k * i + j];
-                    index |= h << (bin_size_log2 * j);
-                }
-                index & ((1 << range_pow) - 1)
-            })
-            .collect()
+/// The hash of the bins `densify` fills, with or without its probe.
+trait HasHash {
+    fn hash(&self) -> usize;
+}
+
+impl HasHash for usize {
+    fn hash(&self) -> usize {
+        *self
     }
 }
 
-impl DensifiedWtaHash {
-    fn densify(&self, hashes: Vec<usize>) -> Vec<usize> {
-        const C: usize = 1234;
-        let h = |i: usize, a: usize| (i * 1234 + a * 567) % self.size;
-        let mut densified_hashes = Vec::with_capacity(self.size);
-        'a: for i in 0..self.size {
-            let mut next = i;
-            let mut attempt = 0;
-            while hashes[next] == 0 {
-                attempt += 1;
-                next = h(i, attempt);
-                if attempt == 100 {
-                    densified_hashes.push(0); // ?
-                    continue 'a;
-                }
-            }
-            densified_hashes.push(hashes[next] + attempt * C);
-        }
-        densified_hashes
+impl HasHash for Probe {
+    fn hash(&self) -> usize {
+        self.hash
     }
 }
 
 #[test]
 fn test() {
-    let hash = DensifiedWtaHash::new(4, 8);
-    assert_eq!(
-        hash.hash(&[0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]),
-        hash.hash_sparse(&[], &[])
-    );
-    assert_eq!(
-        hash.hash(&[0.5, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]),
-        hash.hash_sparse(&[0.5], &[0])
-    );
-    assert_eq!(
-        hash.hash(&[1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]),
-        hash.hash_sparse(&[1.0], &[0])
-    );
-    assert_eq!(
-        hash.hash(&[0.0, 0.5, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]),
-        hash.hash_sparse(&[0.5], &[1])
-    );
-    assert_eq!(
-        hash.hash(&[0.0, 0.5, 0.0, 0.4, 0.0, 0.3, 0.0, 0.2]),
-        hash.hash_sparse(&[0.5, 0.4, 0.3, 0.2], &[1, 3, 5, 7])
-    );
+    use rand::{rngs::StdRng, Rng, SeedableRng};
 
-    let hashes = hash.hash(&[0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
-    dbg!(&hashes);
-    dbg!(DensifiedWtaHash::hashes_to_indices(&hashes, 2, 2, 10));
-    let hashes = hash.hash(&[0.0, 0.5, 0.0, 0.4, 0.0, 0.3, 0.0, 0.2]);
-    dbg!(&hashes);
-    dbg!(DensifiedWtaHash::hashes_to_indices(&hashes, 2, 2, 10));
+    // `getHash` and `getRandDoubleHash` of the original, transcribed with
+    // its `int` arithmetic and `INT_MIN` sentinels.
+    fn reference(hasher: &DensifiedWtaHash, data: &[f32], indices: &[usize]) -> Vec<i32> {
+        let rand_double_hash = |binid: i32, count: i32| -> i32 {
+            let tohash = (((binid + 1) << 10) + count) as u32;
+            ((hasher.rand_hash.wrapping_mul(tohash) << 3) >> (32 - hasher.log_size)) as i32
+        };
+        let num_hashes = hasher.size as i32;
+        let mut hashes = vec![i32::MIN; hasher.size];
+        let mut values = vec![i32::MIN as f32; hasher.size];
+        for p in 0..hasher.permute {
+            for i in 0..data.len() {
+                let slot = p * hasher.number_of_bits + indices[i];
+                let binid = hasher.bin_ids[slot] as i32;
+                if binid < num_hashes && values[binid as usize] < data[i] {
+                    values[binid as usize] = data[i];
+                    hashes[binid as usize] = hasher.positions[slot] as i32;
+                }
+            }
+        }
+        let mut hash_array = vec![0; hasher.size];
+        for i in 0..num_hashes {
+            let mut next = hashes[i as usize];
+            let mut count = 0;
+            while next == i32::MIN {
+                count += 1;
+                let index = rand_double_hash(i, count).min(num_hashes - 1);
+                next = hashes[index as usize];
+                if count > 100 {
+                    break;
+                }
+            }
+            hash_array[i as usize] = next;
+        }
+        hash_array
+    }
+
+    let mut rng = StdRng::seed_from_u64(0);
+    for &(size, number_of_bits) in &[(16, 128), (300, 128), (64, 1000)] {
+        let hasher =
+            DensifiedWtaHash::new(size, number_of_bits, &HasherConfig::default(), &mut rng);
+        for &nnz in &[0, 1, 5, 50] {
+            let mut indices: Vec<usize> = (0..number_of_bits).collect();
+            for i in 0..nnz {
+                indices.swap(i, rng.gen_range(i..number_of_bits));
+            }
+            indices.truncate(nnz);
+            let data: Vec<f32> = (0..nnz).map(|_| rng.gen_range(-2..3) as f32).collect();
+            let expected: Vec<_> = reference(&hasher, &data, &indices)
+                .into_iter()
+                .map(|h| if h == i32::MIN { 0 } else { h as usize })
+                .collect();
+            assert_eq!(hasher.hash_sparse(&data, &indices), expected);
+
+            let mut dense = vec![0.0; number_of_bits];
+            for (&i, &value) in indices.iter().zip(&data) {
+                dense[i] = value;
+            }
+            let all: Vec<_> = (0..number_of_bits).collect();
+            let expected: Vec<_> = reference(&hasher, &dense, &all)
+                .into_iter()
+                .map(|h| h as usize)
+                .collect();
+            assert_eq!(hasher.hash(&dense), expected);
+        }
+    }
+
+    // Worked out by hand in 32-bit arithmetic.
+    let mut hasher = DensifiedWtaHash::new(16, 8, &HasherConfig::default(), &mut rng);
+    hasher.rand_hash = 12345;
+    assert_eq!(hasher.rand_double_hash(2, 1), 1);
+    hasher.rand_hash = i32::MAX as u32;
+    assert_eq!(hasher.rand_double_hash(0, 1), 15);
+    let mut hasher = DensifiedWtaHash::new(64, 8, &HasherConfig::default(), &mut rng);
+    hasher.rand_hash = i32::MAX as u32;
+    assert_eq!(hasher.rand_double_hash(5, 3), 63);
+
+    // The hashes borrowed from other bins are offset within the bin size.
+    let hasher = DensifiedWtaHash::new(
+        32,
+        64,
+        &HasherConfig {
+            densification: Densification::Probing,
+            ..HasherConfig::default()
+        },
+        &mut rng,
+    );
+    let hashes = hasher.hash_sparse(&[1.0], &[3]);
+    let own = hasher.bins(std::iter::once((3, 1.0)), |bin, _, _| bin.hash);
+    assert_eq!(own.iter().filter(|&&h| h != EMPTY).count(), 4);
+    for (&hash, &own_hash) in hashes.iter().zip(&own) {
+        assert!(hash < 8);
+        assert!(own_hash == EMPTY || hash == own_hash);
+    }
+    assert!(hashes.iter().any(|h| !own.contains(h)));
 
-    let hash = DensifiedWtaHash::new(100, 50);
-    let hashes = hash.hash_sparse(&[1.0], &[0]);
-    dbg!(&hashes);
+    // The probes agree with the hashes, for any bin size.
+    for &densification in &[Densification::Original, Densification::Probing] {
+        let config = HasherConfig {
+            densification,
+            bin_size: 5,
+        };
+        let hasher = DensifiedWtaHash::new(40, 30, &config, &mut rng);
+        for _ in 0..20 {
+            let indices: Vec<usize> = (0..30).filter(|_| rng.gen_bool(0.3)).collect();
+            let weights: Vec<f32> = indices
+                .iter()
+                .map(|_| rng.gen_range(-2..3) as f32)
+                .collect();
+            let probes = hasher.probe_sparse(&weights, &indices);
+            let hashes = hasher.hash_sparse(&weights, &indices);
+            assert_eq!(probes.iter().map(|p| p.hash).collect::<Vec<_>>(), hashes);
+            assert!(probes.iter().all(|p| p.margin >= 0.0));
+            assert!(probes.iter().all(|p| p.hash < 5 && p.runner_up < 5));
+        }
+    }
 }
@@ -1,6 +1,153 @@
+use std::io::{self, Read, Write};
+
+use rand::rngs::StdRng;
+
+use crate::checkpoint::invalid_data;
+
+/// How `DensifiedWtaHash` fills the bins none of the input fell into.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Densification {
+    /// As the original SLIDE: borrows the hash of the first nonempty bin
+    /// along a sequence of random double hashes of the bin, giving up (and
+    /// hashing to 0) after 101 probes.
+    Original = 0,
+    /// Probes the bins `(i * 1234 + attempt * 567) % size` and borrows the
+    /// hash of the first nonempty one, offset by `attempt * 1234` modulo the
+    /// bin size so that it mostly differs from the hash of that bin. Gives
+    /// up after 100 probes.
+    Probing = 1,
+}
+
+impl Densification {
+    pub(crate) fn from_u8(value: u8) -> io::Result<Self> {
+        match value {
+            0 => Ok(Densification::Original),
+            1 => Ok(Densification::Probing),
+            _ => Err(invalid_data("unknown densification")),
+        }
+    }
+}
+
+/// Options of the hashers, ignored by the ones they do not apply to.
+#[derive(Clone, Copy, Debug)]
+pub struct HasherConfig {
+    pub densification: Densification,
+    /// Number of inputs compared by every WTA hash, which ranges over
+    /// `0..bin_size`.
+    pub bin_size: usize,
+}
+
+impl Default for HasherConfig {
+    fn default() -> Self {
+        HasherConfig {
+            densification: Densification::Original,
+            bin_size: 8,
+        }
+    }
+}
+
+/// A hash along with the value it switches to first when its input is
+/// perturbed, for multi-probe queries.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Probe {
+    pub hash: usize,
+    pub runner_up: usize,
+    /// How far the input is from switching, comparable between the hashes
+    /// of one hasher. Infinite if the hash never switches.
+    pub margin: f32,
+}
+
+impl Probe {
+    pub fn exact(hash: usize) -> Self {
+        Probe {
+            hash,
+            runner_up: hash,
+            margin: f32::INFINITY,
+        }
+    }
+}
+
 pub trait Hasher: Send + Sync {
-    fn new(size: usize, number_of_bits_to_hash: usize) -> Self;
+    /// Identifies the hasher in checkpoints, distinct for every hasher.
+    const TAG: u8;
+
+    fn new(
+        size: usize,
+        number_of_bits_to_hash: usize,
+        config: &HasherConfig,
+        rng: &mut StdRng,
+    ) -> Self;
     fn hash(&self, weights: &[f32]) -> Vec<usize>;
     fn hash_sparse(&self, weights: &[f32], indices: &[usize]) -> Vec<usize>;
-    fn hashes_to_indices(hashes: &[usize], k: usize, l: usize, range_pow: usize) -> Vec<usize>;
+    /// `hash_sparse` along with the runner-up of every hash. Hashers without
+    /// a notion of runner-up never switch.
+    fn probe_sparse(&self, weights: &[f32], indices: &[usize]) -> Vec<Probe> {
+        self.hash_sparse(weights, indices)
+            .into_iter()
+            .map(Probe::exact)
+            .collect()
+    }
+    /// The bucket index in every of the `l` tables of the `k` hashes
+    /// `hashes[k * i..k * (i + 1)]` of table `i`.
+    fn hashes_to_indices(
+        &self,
+        hashes: &[usize],
+        k: usize,
+        l: usize,
+        range_pow: usize,
+    ) -> Vec<usize>;
+    /// Writes the random state of the hasher, see `read_from`.
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()>;
+    /// Restores a hasher hashing exactly like the one passed to `write_to`.
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Self>
+    where
+        Self: Sized;
+}
+
+/// `hashes_to_indices` for hashes ranging over `0..radix`, read as the digits
+/// of a number in base `radix`, least significant first, taken modulo
+/// `1 << range_pow`. Distinct hashes get distinct indices as long as
+/// `radix.pow(k) <= 1 << range_pow`.
+pub(crate) fn pack_hashes(
+    hashes: &[usize],
+    k: usize,
+    l: usize,
+    range_pow: usize,
+    radix: usize,
+) -> Vec<usize> {
+    (0..l)
+        .map(|i| {
+            let index = hashes[k * i..k * (i + 1)]
+                .iter()
+                .rev()
+                .fold(0usize, |index, &h| {
+                    index.wrapping_mul(radix).wrapping_add(h)
+                });
+            index & ((1 << range_pow) - 1)
+        })
+        .collect()
+}
+
+/// The probe of a bin of values: the position of its first maximum, then
+/// that of the largest of the other values.
+pub(crate) fn bin_probe(values: impl Iterator<Item = f32>) -> Probe {
+    let mut probe = Probe::exact(0);
+    let (mut best, mut second) = (f32::NEG_INFINITY, f32::NEG_INFINITY);
+    for (j, value) in values.enumerate() {
+        if j == 0 || value > best {
+            if j > 0 {
+                probe.runner_up = probe.hash;
+                second = best;
+            }
+            probe.hash = j;
+            best = value;
+        } else if probe.runner_up == probe.hash || value > second {
+            probe.runner_up = j;
+            second = value;
+        }
+    }
+    if probe.runner_up != probe.hash {
+        probe.margin = best - second;
+    }
+    probe
 }
@@ -1,28 +1,183 @@
-use std::collections::HashSet;
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Read, Write},
+};
 
-use rand::{seq::SliceRandom, Rng};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
 use rayon::prelude::*;
 
-use crate::{hasher::Hasher, lsh::Lsh, node::Node, param::Param};
+use crate::{
+    bucket::BucketPolicy,
+    checkpoint::*,
+    hasher::{Densification, Hasher, HasherConfig},
+    lsh::{Lsh, MAX_RANGE_POW},
+    network::{LayerConfig, UpdateMode},
+    node::Node,
+    optimizer::Optimizer,
+    param::Params,
+};
 
-#[derive(Clone, Copy)]
+/// Number of nodes hashed before their bucket indices are added to the tables.
+const REHASH_CHUNK_SIZE: usize = 1 << 16;
+
+/// The next step of the nodes of a layer read without their optimizer state,
+/// standing for whichever step it resumes at.
+const RESUME_STEP: usize = usize::MAX;
+
+// The purposes of the random streams of a layer, see `Layer::rng`.
+const NODE_ORDER: u64 = 0;
+const HASHER: u64 = 1;
+const HASH_TABLES: u64 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum NodeType {
     Relu,
+    /// Normalizes the values of the active nodes into probabilities.
     Softmax,
+    Sigmoid,
+    Tanh,
+    Linear,
+    /// ReLU letting the given nonnegative slope through for negative inputs.
+    LeakyRelu(f32),
+    /// GELU, with the tanh approximation.
+    Gelu,
+}
+
+impl NodeType {
+    /// Replaces the pre-activations of the active nodes by their values.
+    pub(crate) fn activate(self, values: &mut [f32]) {
+        match self {
+            NodeType::Softmax => {
+                let max_value = values.iter().fold(0.0f32, |a, b| a.max(*b));
+                for value in values.iter_mut() {
+                    *value = (*value - max_value).exp();
+                }
+                let sum_value: f32 = values.iter().sum();
+                for value in values {
+                    *value /= sum_value;
+                }
+            }
+            node_type => {
+                for value in values {
+                    *value = node_type.activation(*value);
+                }
+            }
+        }
+    }
+
+    /// Turns the deltas of the values of the active nodes into those of
+    /// their pre-activations.
+    pub(crate) fn back_propagate(self, layer_status: &mut LayerStatus) {
+        let LayerStatus {
+            active_values,
+            pre_activations,
+            deltas,
+            ..
+        } = layer_status;
+        match self {
+            NodeType::Softmax => {
+                let mean: f32 = active_values.iter().zip(&*deltas).map(|(y, d)| y * d).sum();
+                for (delta, &y) in deltas.iter_mut().zip(&*active_values) {
+                    *delta = y * (*delta - mean);
+                }
+            }
+            node_type => {
+                for (i, delta) in deltas.iter_mut().enumerate() {
+                    let y = active_values[i];
+                    *delta *= node_type.derivative(pre_activations.get(i).cloned().unwrap_or(y), y);
+                }
+            }
+        }
+    }
+
+    /// The activation of the elementwise node types.
+    fn activation(self, x: f32) -> f32 {
+        match self {
+            NodeType::Relu => x.max(0.0),
+            NodeType::Softmax => unreachable!("softmax is not elementwise"),
+            NodeType::Linear => x,
+            NodeType::Sigmoid => 1.0 / (1.0 + (-x).exp()),
+            NodeType::Tanh => x.tanh(),
+            NodeType::LeakyRelu(slope) => {
+                if x > 0.0 {
+                    x
+                } else {
+                    slope * x
+                }
+            }
+            NodeType::Gelu => 0.5 * x * (1.0 + gelu_tanh(x)),
+        }
+    }
+
+    /// The derivative of `activation` at `x`, given its value `y`. `x` is
+    /// only read by the node types keeping the pre-activations.
+    fn derivative(self, x: f32, y: f32) -> f32 {
+        match self {
+            NodeType::Relu => (y > 0.0) as u8 as f32,
+            NodeType::Softmax => unreachable!("softmax is not elementwise"),
+            NodeType::Linear => 1.0,
+            NodeType::Sigmoid => y * (1.0 - y),
+            NodeType::Tanh => 1.0 - y * y,
+            NodeType::LeakyRelu(slope) => {
+                if y > 0.0 {
+                    1.0
+                } else {
+                    slope
+                }
+            }
+            NodeType::Gelu => {
+                let t = gelu_tanh(x);
+                0.5 * (1.0 + t)
+                    + 0.5 * x * (1.0 - t * t) * GELU_SCALE * (1.0 + 3.0 * GELU_CUBIC * x * x)
+            }
+        }
+    }
+
+    /// Whether `derivative` needs the pre-activations.
+    fn keeps_pre_activations(self) -> bool {
+        matches!(self, NodeType::Gelu)
+    }
+}
+
+const GELU_SCALE: f32 = 0.797_884_6; // sqrt(2 / pi)
+const GELU_CUBIC: f32 = 0.044715;
+
+fn gelu_tanh(x: f32) -> f32 {
+    (GELU_SCALE * (x + GELU_CUBIC * x * x * x)).tanh()
+}
+
+/// How the active nodes are picked from the buckets hit by the LSH query.
+///
+/// `sparsity * size` nodes are aimed for by `Vanilla` and `TopK`; the set is
+/// then padded with random nodes up to the layer's minimum in every mode.
+#[derive(Clone, Copy)]
+pub enum SamplingMode {
+    /// Union of every bucket hit.
+    Union,
+    /// Visits the tables in random order, stopping once enough nodes are found.
+    Vanilla,
+    /// Keeps the nodes colliding in the most tables.
+    TopK,
+    /// Keeps the nodes colliding in at least the given number of tables.
+    Threshold(usize),
 }
 
 #[derive(Default)]
 pub struct LayerStatus {
     pub active_nodes: Vec<usize>,
     pub active_values: Vec<f32>,
+    /// The values before activation, kept only for the node types whose
+    /// derivative needs them.
+    pub pre_activations: Vec<f32>,
     pub deltas: Vec<f32>,
 }
 
 impl LayerStatus {
-    pub fn from_input(indices: &[usize], values: &[f32]) -> Self {
+    pub fn from_input(indices: &[u32], values: &[f32]) -> Self {
         LayerStatus {
-            active_nodes: indices.to_vec(),
+            active_nodes: indices.iter().map(|&i| i as usize).collect(),
             active_values: values.to_vec(),
+            pre_activations: Vec::new(),
             deltas: vec![0.0; indices.len()],
         }
     }
@@ -34,89 +189,387 @@ impl LayerStatus {
 
 pub struct Layer<H: Hasher> {
     node_type: NodeType,
-    nodes: Vec<Node>,
+    // The weights of node `i` are the `i`th row of `previous_layer_num_of_nodes`.
+    weights: Params,
+    biases: Params,
     rand_ids: Vec<u32>,
     k: usize,
     l: usize,
+    range_pow: usize,
+    bucket_size: usize,
+    bucket_policy: BucketPolicy,
+    probes_per_table: usize,
     previous_layer_num_of_nodes: usize,
     pub sparsity: f32,
+    sampling_mode: SamplingMode,
+    hasher_config: HasherConfig,
     hasher: H,
     hash_tables: Lsh,
     min_active_nodes: usize,
+    // The nodes and inputs active for some case of the batch.
+    touched_nodes: Vec<bool>,
+    touched_inputs: Vec<bool>,
+    // The first step each node has not been updated at yet.
+    next_steps: Vec<usize>,
+    // Every random draw of the layer derives from it, along with the number
+    // of `update_table` and `random_nodes` calls for theirs, so that a layer
+    // read back draws as the one written would.
+    seed: u64,
+    rebuilds: u64,
+    shuffles: u64,
 }
 
 impl<H: Hasher> Layer<H> {
-    pub fn new(
-        number_of_nodes: usize,
-        previous_layer_num_of_nodes: usize,
-        node_type: NodeType,
-        k: usize,
-        l: usize,
-        range_pow: usize,
-        sparsity: f32,
-    ) -> Self {
-        let mut rand_ids: Vec<_> = (0..number_of_nodes as u32).collect();
-        let mut rng = rand::thread_rng();
-        rand_ids.shuffle(&mut rng);
-
-        let mut nodes = Vec::with_capacity(number_of_nodes);
-        for _ in 0..number_of_nodes {
-            let mut weights = Vec::with_capacity(previous_layer_num_of_nodes);
-            weights.resize_with(previous_layer_num_of_nodes, || {
-                Param::new(rng.gen_range(0.0..0.01))
-            });
-            let bias = Param::new(rng.gen_range(0.0..0.01));
+    /// Creates a layer whose parameters, hashers and samples are all drawn
+    /// from random streams derived from `seed`.
+    pub fn new(config: &LayerConfig, previous_layer_num_of_nodes: usize, seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
 
-            nodes.push(Node::new(weights, bias));
+        let mut weights = Vec::with_capacity(config.size * previous_layer_num_of_nodes);
+        let mut biases = Vec::with_capacity(config.size);
+        for _ in 0..config.size {
+            weights.extend((0..previous_layer_num_of_nodes).map(|_| rng.gen_range(0.0..0.01)));
+            biases.push(rng.gen_range(0.0..0.01));
         }
 
-        let hasher = H::new(k * l, previous_layer_num_of_nodes);
-        let hash_tables = Lsh::new(k, l, range_pow);
+        let hasher = H::new(
+            config.k * config.l,
+            previous_layer_num_of_nodes,
+            &config.hasher,
+            &mut rng,
+        );
+
+        let mut layer = Self::from_parts(
+            config,
+            previous_layer_num_of_nodes,
+            Params::new(weights),
+            Params::new(biases),
+            hasher,
+            seed,
+        );
+        layer.rehash();
+        layer
+    }
+
+    /// Assembles a layer with empty hash tables.
+    fn from_parts(
+        config: &LayerConfig,
+        previous_layer_num_of_nodes: usize,
+        weights: Params,
+        biases: Params,
+        hasher: H,
+        seed: u64,
+    ) -> Self {
+        let &LayerConfig {
+            size: number_of_nodes,
+            node_type,
+            k,
+            l,
+            range_pow,
+            sparsity,
+            sampling_mode,
+            bucket_size,
+            bucket_policy,
+            probes_per_table,
+            hasher: hasher_config,
+        } = config;
+        assert!(
+            0 < bucket_size && bucket_size <= u32::MAX as usize,
+            "bucket_size must be in 1..=u32::MAX, got {}",
+            bucket_size
+        );
+
+        let hash_tables = Lsh::new(
+            k,
+            l,
+            range_pow,
+            bucket_size,
+            bucket_policy,
+            crate::mix_seed(seed, HASH_TABLES, 0),
+        );
 
         let mut layer = Self {
             node_type,
-            nodes,
-            rand_ids,
+            weights,
+            biases,
+            rand_ids: Vec::new(),
             k,
             l,
+            range_pow,
+            bucket_size,
+            bucket_policy,
+            probes_per_table,
             previous_layer_num_of_nodes,
+            hasher_config,
             hasher,
             hash_tables,
             sparsity,
+            sampling_mode,
             min_active_nodes: 1000,
+            touched_nodes: vec![false; number_of_nodes],
+            touched_inputs: vec![false; previous_layer_num_of_nodes],
+            next_steps: vec![0; number_of_nodes],
+            seed,
+            rebuilds: 0,
+            shuffles: 0,
         };
+        layer.rand_ids = layer.node_order();
+        layer
+    }
 
-        layer.rehash();
+    /// The `count`th random stream of the layer for `purpose`.
+    fn rng(&self, purpose: u64, count: u64) -> StdRng {
+        StdRng::seed_from_u64(crate::mix_seed(self.seed, purpose, count))
+    }
 
-        layer
+    /// The random order of the nodes after `shuffles` calls of
+    /// `random_nodes`.
+    fn node_order(&self) -> Vec<u32> {
+        let mut rand_ids: Vec<_> = (0..self.size() as u32).collect();
+        rand_ids.shuffle(&mut self.rng(NODE_ORDER, self.shuffles));
+        rand_ids
+    }
+
+    /// Writes the configuration, the hasher and the parameters of the layer
+    /// with `state_size` optimizer state values each, followed by the last
+    /// update steps and the hash tables if there is a state; without one,
+    /// `read_from` rebuilds the tables from the parameters.
+    pub fn write_to<W: Write>(&self, w: &mut W, state_size: usize) -> io::Result<()> {
+        write_usize(w, self.size())?;
+        write_u8(
+            w,
+            match self.node_type {
+                NodeType::Relu => 0,
+                NodeType::Softmax => 1,
+                NodeType::Sigmoid => 2,
+                NodeType::Tanh => 3,
+                NodeType::Linear => 4,
+                NodeType::LeakyRelu(_) => 5,
+                NodeType::Gelu => 6,
+            },
+        )?;
+        if let NodeType::LeakyRelu(slope) = self.node_type {
+            write_f32(w, slope)?;
+        }
+        write_usize(w, self.k)?;
+        write_usize(w, self.l)?;
+        write_usize(w, self.range_pow)?;
+        write_f32(w, self.sparsity)?;
+        match self.sampling_mode {
+            SamplingMode::Union => write_u8(w, 0)?,
+            SamplingMode::Vanilla => write_u8(w, 1)?,
+            SamplingMode::TopK => write_u8(w, 2)?,
+            SamplingMode::Threshold(threshold) => {
+                write_u8(w, 3)?;
+                write_usize(w, threshold)?;
+            }
+        }
+        write_usize(w, self.bucket_size)?;
+        write_u8(
+            w,
+            match self.bucket_policy {
+                BucketPolicy::Fifo => 0,
+                BucketPolicy::Reservoir => 1,
+                BucketPolicy::RejectWhenFull => 2,
+            },
+        )?;
+        write_usize(w, self.probes_per_table)?;
+        write_u8(w, self.hasher_config.densification as u8)?;
+        write_usize(w, self.hasher_config.bin_size)?;
+        write_u64(w, self.seed)?;
+        write_u64(w, self.rebuilds)?;
+        write_u64(w, self.shuffles)?;
+        write_u8(w, H::TAG)?;
+        self.hasher.write_to(w)?;
+        for i in 0..self.size() {
+            let row = i * self.previous_layer_num_of_nodes;
+            for j in row..row + self.previous_layer_num_of_nodes {
+                self.weights.write_param(w, j, state_size)?;
+            }
+            self.biases.write_param(w, i, state_size)?;
+        }
+        if state_size > 0 {
+            write_usizes(w, &self.next_steps)?;
+            self.hash_tables.write_to(w)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(
+        r: &mut R,
+        previous_layer_num_of_nodes: usize,
+        state_size: usize,
+    ) -> io::Result<Self> {
+        let size = read_usize(r)?;
+        let node_type = match read_u8(r)? {
+            0 => NodeType::Relu,
+            1 => NodeType::Softmax,
+            2 => NodeType::Sigmoid,
+            3 => NodeType::Tanh,
+            4 => NodeType::Linear,
+            5 => NodeType::LeakyRelu(read_f32(r)?),
+            6 => NodeType::Gelu,
+            _ => return Err(invalid_data("unknown node type")),
+        };
+        let k = read_usize(r)?;
+        let l = read_usize(r)?;
+        let range_pow = read_usize(r)?;
+        if k == 0 || l == 0 || k.checked_mul(l).is_none() || range_pow > MAX_RANGE_POW {
+            return Err(invalid_data("hash table shape out of range"));
+        }
+        let sparsity = read_f32(r)?;
+        let sampling_mode = match read_u8(r)? {
+            0 => SamplingMode::Union,
+            1 => SamplingMode::Vanilla,
+            2 => SamplingMode::TopK,
+            3 => SamplingMode::Threshold(read_usize(r)?),
+            _ => return Err(invalid_data("unknown sampling mode")),
+        };
+        let bucket_size = read_usize(r)?;
+        if bucket_size == 0 || bucket_size > u32::MAX as usize {
+            return Err(invalid_data("bucket size out of range"));
+        }
+        let bucket_policy = match read_u8(r)? {
+            0 => BucketPolicy::Fifo,
+            1 => BucketPolicy::Reservoir,
+            2 => BucketPolicy::RejectWhenFull,
+            _ => return Err(invalid_data("unknown bucket policy")),
+        };
+        let probes_per_table = read_usize(r)?;
+        let hasher_config = HasherConfig {
+            densification: Densification::from_u8(read_u8(r)?)?,
+            bin_size: read_usize(r)?,
+        };
+        let seed = read_u64(r)?;
+        let rebuilds = read_u64(r)?;
+        let shuffles = read_u64(r)?;
+        let config = LayerConfig {
+            size,
+            node_type,
+            k,
+            l,
+            range_pow,
+            sparsity,
+            sampling_mode,
+            bucket_size,
+            bucket_policy,
+            probes_per_table,
+            hasher: hasher_config,
+        };
+        if read_u8(r)? != H::TAG {
+            return Err(invalid_data("saved with another hasher"));
+        }
+        let hasher = H::read_from(r)?;
+        let mut weights = Params::new(Vec::new());
+        let mut biases = Params::new(Vec::new());
+        for _ in 0..size {
+            for _ in 0..previous_layer_num_of_nodes {
+                weights.read_param(r, state_size)?;
+            }
+            biases.read_param(r, state_size)?;
+        }
+        let mut layer = Self::from_parts(
+            &config,
+            previous_layer_num_of_nodes,
+            weights,
+            biases,
+            hasher,
+            seed,
+        );
+        layer.rebuilds = rebuilds;
+        layer.shuffles = shuffles;
+        layer.rand_ids = layer.node_order();
+        if state_size > 0 {
+            layer.next_steps = read_usizes(r)?;
+            if layer.next_steps.len() != size {
+                return Err(invalid_data("wrong number of update steps"));
+            }
+            layer.hash_tables.read_from(r, size)?;
+        } else {
+            layer.next_steps = vec![RESUME_STEP; size];
+            layer.rehash();
+        }
+        Ok(layer)
+    }
+
+    pub fn size(&self) -> usize {
+        self.biases.len()
+    }
+
+    pub fn node_type(&self) -> NodeType {
+        self.node_type
+    }
+
+    /// The weights of all nodes, row by row, see `node`.
+    pub fn weights(&self) -> &Params {
+        &self.weights
+    }
+
+    pub fn weights_mut(&mut self) -> &mut Params {
+        &mut self.weights
+    }
+
+    pub fn biases(&self) -> &Params {
+        &self.biases
+    }
+
+    pub fn biases_mut(&mut self) -> &mut Params {
+        &mut self.biases
+    }
+
+    pub fn node(&self, id: usize) -> Node<'_> {
+        let row =
+            id * self.previous_layer_num_of_nodes..(id + 1) * self.previous_layer_num_of_nodes;
+        Node::new(
+            &self.weights.values()[row.clone()],
+            self.biases.values()[id],
+            &self.weights.errors()[row],
+            &self.biases.errors()[id],
+        )
     }
 
     pub fn update_table(&mut self) {
-        self.hasher = H::new(self.k * self.l, self.previous_layer_num_of_nodes);
+        self.rebuilds += 1;
+        self.hasher = H::new(
+            self.k * self.l,
+            self.previous_layer_num_of_nodes,
+            &self.hasher_config,
+            &mut self.rng(HASHER, self.rebuilds),
+        );
     }
 
     pub fn rehash(&mut self) {
         self.hash_tables.clear();
-        let hasher = &self.hasher;
-        let hash_tables = &self.hash_tables;
-        self.nodes.par_iter().enumerate().for_each(|(i, node)| {
-            let hashes = hasher.hash(&node.weights.iter().map(|w| w.value).collect::<Vec<_>>());
-            let hash_indices = hash_tables.hashes_to_indices::<H>(&hashes);
-            #[allow(mutable_transmutes)]
-            unsafe { std::mem::transmute::<_, &mut Lsh>(hash_tables) }.add(&hash_indices, i as u32);
-        });
+        let chunk_size = REHASH_CHUNK_SIZE * self.previous_layer_num_of_nodes;
+        for (i, rows) in self.weights.values().chunks(chunk_size).enumerate() {
+            let hasher = &self.hasher;
+            let hash_tables = &self.hash_tables;
+            let hash_indices: Vec<_> = rows
+                .par_chunks(self.previous_layer_num_of_nodes)
+                .map(|weights| hash_tables.hashes_to_indices(hasher, &hasher.hash(weights)))
+                .collect();
+            self.hash_tables
+                .add_batch(&hash_indices, (i * REHASH_CHUNK_SIZE) as u32);
+        }
     }
 
     pub fn random_nodes(&mut self) {
-        self.rand_ids.shuffle(&mut rand::thread_rng());
+        self.shuffles += 1;
+        self.rand_ids = self.node_order();
     }
 
+    /// Activates the nodes of `layer_statuses[1]` for the values of
+    /// `layer_statuses[0]`. Returns how many of `force_activate_nodes` the
+    /// LSH query found, all of them if every node is active. The nodes
+    /// sampled beyond the query are drawn from `seed`, so the same seed
+    /// activates the same nodes.
     pub fn query_active_node_and_compute_activations(
         &self,
         layer_statuses: &mut [LayerStatus],
         force_activate_nodes: &[u32],
         sparsity: f32,
-    ) {
+        seed: u64,
+    ) -> usize {
         let mut it = layer_statuses.iter_mut();
         let LayerStatus {
             ref active_nodes,
@@ -125,95 +578,410 @@ impl<H: Hasher> Layer<H> {
         } = it.next().unwrap();
         let layer_status = it.next().unwrap();
 
+        let mut recalled = force_activate_nodes.len();
         layer_status.active_nodes = if sparsity == 1.0 {
-            (0..self.nodes.len()).collect()
+            (0..self.size()).collect()
         } else {
-            // TODO: implement Modes
-
-            let hashes = self.hasher.hash_sparse(&active_values, &active_nodes);
-            let hash_indices = self.hash_tables.hashes_to_indices::<H>(&hashes);
-            let actives = self.hash_tables.get_ids(&hash_indices);
-            // we now have a sparse array of indices of active nodes
+            let hash_indices = if self.probes_per_table > 1 {
+                let probes = self.hasher.probe_sparse(active_values, active_nodes);
+                self.hash_tables
+                    .probe_indices(&self.hasher, &probes, self.probes_per_table)
+            } else {
+                let hashes = self.hasher.hash_sparse(active_values, active_nodes);
+                let hash_indices = self.hash_tables.hashes_to_indices(&self.hasher, &hashes);
+                hash_indices.into_iter().map(|i| vec![i]).collect()
+            };
+            let target = (self.size() as f32 * sparsity).ceil() as usize;
+            let mut rng = StdRng::seed_from_u64(seed ^ self.seed);
 
             // Get candidates from hashset
             let mut active_nodes = HashSet::<u32>::new();
             active_nodes.extend(force_activate_nodes);
-            active_nodes.extend(actives);
+            let mut sampled = Vec::new();
+            match self.sampling_mode {
+                SamplingMode::Union => {
+                    sampled = self.hash_tables.get_ids(&hash_indices);
+                }
+                SamplingMode::Vanilla => {
+                    let offset = rng.gen_range(0..self.l);
+                    for i in 0..self.l {
+                        if active_nodes.len() >= target {
+                            break;
+                        }
+                        let i = (i + offset) % self.l;
+                        for &index in &hash_indices[i] {
+                            let bucket = self.hash_tables.get_bucket(i, index);
+                            active_nodes.extend(bucket);
+                            sampled.extend_from_slice(bucket);
+                        }
+                    }
+                }
+                SamplingMode::TopK => {
+                    let mut counts: Vec<_> =
+                        self.count_collisions(&hash_indices).into_iter().collect();
+                    counts.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+                    sampled.extend(counts.iter().take(target).map(|(id, _)| *id));
+                }
+                SamplingMode::Threshold(threshold) => {
+                    let counts = self.count_collisions(&hash_indices);
+                    sampled.extend(
+                        counts
+                            .into_iter()
+                            .filter(|(_, count)| *count >= threshold)
+                            .map(|(id, _)| id),
+                    );
+                }
+            }
+            active_nodes.extend(&sampled);
+            if !force_activate_nodes.is_empty() {
+                // Removed once found, as the samples may repeat.
+                let mut labels: HashSet<u32> = force_activate_nodes.iter().cloned().collect();
+                recalled = sampled.iter().filter(|id| labels.remove(id)).count();
+            }
 
-            let offset = rand::random::<usize>() % self.nodes.len();
-            for i in 0..self.nodes.len() {
+            let offset = rng.gen_range(0..self.size());
+            for i in 0..self.size() {
                 if active_nodes.len() >= self.min_active_nodes {
                     break;
                 }
-                let i = (i + offset) % self.nodes.len();
+                let i = (i + offset) % self.size();
                 active_nodes.insert(self.rand_ids[i]);
             }
 
-            active_nodes.iter().map(|v| *v as usize).collect()
+            // In order, as the hash set iterates in an order of its own.
+            let mut active_nodes: Vec<_> = active_nodes.iter().map(|v| *v as usize).collect();
+            active_nodes.sort_unstable();
+            active_nodes
         };
 
+        // A fully active previous layer, in order, needs no gathering.
+        let dense = active_nodes.len() == self.previous_layer_num_of_nodes
+            && active_nodes.iter().enumerate().all(|(i, &id)| i == id);
         layer_status.active_values.clear();
         for id in layer_status.active_nodes.iter().cloned() {
+            let node = self.node(id);
+            layer_status.active_values.push(if dense {
+                node.compute_dense_value(active_values)
+            } else {
+                node.compute_value(active_nodes, active_values)
+            });
+        }
+        layer_status.pre_activations.clear();
+        if self.node_type.keeps_pre_activations() {
             layer_status
-                .active_values
-                .push(self.nodes[id].compute_value(&active_nodes, &active_values));
+                .pre_activations
+                .extend_from_slice(&layer_status.active_values);
         }
-        self.activate(&mut layer_status.active_values);
+        self.node_type.activate(&mut layer_status.active_values);
 
         layer_status.deltas.clear();
         layer_status
             .deltas
             .resize(layer_status.active_nodes.len(), 0.0);
+
+        recalled
+    }
+
+    fn count_collisions(&self, hash_indices: &[Vec<usize>]) -> HashMap<u32, usize> {
+        let mut counts = HashMap::new();
+        for id in self.hash_tables.get_ids(hash_indices) {
+            *counts.entry(id).or_insert(0) += 1;
+        }
+        counts
     }
 
-    pub fn back_propagate(&mut self, layer_statuses: &mut [LayerStatus]) {
+    /// Back-propagates the deltas of the values of `layer_statuses[1]` into
+    /// the errors of the parameters and the deltas of `layer_statuses[0]`.
+    pub fn back_propagate(&self, layer_statuses: &mut [LayerStatus]) {
+        self.node_type.back_propagate(&mut layer_statuses[1]);
+        self.back_propagate_pre_activations(layer_statuses);
+    }
+
+    /// `back_propagate` for the deltas of the pre-activations, as the losses
+    /// write them.
+    pub fn back_propagate_pre_activations(&self, layer_statuses: &mut [LayerStatus]) {
         let mut it = layer_statuses.iter_mut();
         let prev_layer_status = it.next().unwrap();
         let layer_status = it.next().unwrap();
         for i in 0..layer_status.size() {
             let id = layer_status.active_nodes[i];
-            let value = layer_status.active_values[i];
-            let delta = layer_status.deltas[i];
-            let delta = match self.node_type {
-                NodeType::Relu => {
-                    if value > 0.0 {
-                        delta
-                    } else {
-                        0.0
-                    }
-                }
-                NodeType::Softmax => delta,
-            };
-            self.nodes[id].back_propagate(delta, prev_layer_status);
+            self.node(id)
+                .back_propagate(layer_status.deltas[i], prev_layer_status);
         }
     }
 
-    pub fn update_weights(&mut self, rate: f32) {
-        self.nodes.par_iter_mut().for_each(|node| {
-            for j in 0..node.get_size() {
-                node.weights[j].update(rate);
-            }
-            node.bias.update(rate);
-        });
+    /// Records the nodes of `layer_statuses[1]` and the inputs of
+    /// `layer_statuses[0]` as active for the sparse update modes.
+    pub fn mark_touched(&mut self, layer_statuses: &[LayerStatus]) {
+        for &id in &layer_statuses[0].active_nodes {
+            self.touched_inputs[id] = true;
+        }
+        for &id in &layer_statuses[1].active_nodes {
+            self.touched_nodes[id] = true;
+        }
     }
 
-    fn activate(&self, values: &mut [f32]) {
-        match self.node_type {
-            NodeType::Relu => {
-                for value in values {
-                    *value = value.max(0.0);
+    /// Updates the parameters selected by `mode` from the nodes and inputs
+    /// marked by `mark_touched`, then clears the marks.
+    pub fn update_weights<O: Optimizer>(&mut self, optimizer: &O, step: usize, mode: UpdateMode) {
+        let inputs: Option<Vec<_>> = match mode {
+            UpdateMode::Sparse => Some(
+                (0..self.touched_inputs.len())
+                    .filter(|&i| self.touched_inputs[i])
+                    .collect(),
+            ),
+            UpdateMode::Dense | UpdateMode::Lazy => None,
+        };
+        self.weights
+            .par_chunks_mut(self.previous_layer_num_of_nodes)
+            .zip(self.biases.par_chunks_mut(1))
+            .zip(&mut self.touched_nodes)
+            .zip(&mut self.next_steps)
+            .for_each(|(((mut weights, mut bias), touched), next_step)| {
+                if *next_step == RESUME_STEP {
+                    *next_step = step;
                 }
-            }
-            NodeType::Softmax => {
-                let max_value = values.iter().fold(0.0f32, |a, b| a.max(*b));
-                for value in values.iter_mut() {
-                    *value = (*value - max_value).exp();
+                if mode != UpdateMode::Dense && !*touched {
+                    return;
                 }
-                let sum_value: f32 = values.iter().sum();
-                for value in values {
-                    *value = *value / sum_value;
+                if mode == UpdateMode::Lazy {
+                    let skipped = step.saturating_sub(*next_step);
+                    if skipped > 0 {
+                        for i in 0..weights.len() {
+                            weights.catch_up(i, optimizer, skipped);
+                        }
+                        bias.catch_up(0, optimizer, skipped);
+                    }
                 }
+                match &inputs {
+                    Some(inputs) => {
+                        for &i in inputs {
+                            weights.update(i, optimizer);
+                        }
+                    }
+                    None => {
+                        for i in 0..weights.len() {
+                            weights.update(i, optimizer);
+                        }
+                    }
+                }
+                bias.update(0, optimizer);
+                *touched = false;
+                *next_step = step + 1;
+            });
+        self.touched_inputs.iter_mut().for_each(|t| *t = false);
+    }
+}
+
+#[test]
+fn test() {
+    use crate::{
+        optimizer::{Adam, AdamW},
+        wta_hash::WtaHash,
+    };
+
+    let config = LayerConfig {
+        size: 4,
+        node_type: NodeType::Relu,
+        k: 2,
+        l: 2,
+        range_pow: 4,
+        sparsity: 1.0,
+        sampling_mode: SamplingMode::Union,
+        bucket_size: 8,
+        bucket_policy: BucketPolicy::Fifo,
+        probes_per_table: 1,
+        hasher: HasherConfig::default(),
+    };
+    let column = |layer: &Layer<WtaHash>, i: usize| -> Vec<_> {
+        (0..layer.size())
+            .map(|id| layer.node(id).weights[i])
+            .collect()
+    };
+    let step = |layer: &mut Layer<WtaHash>, adam: &mut Adam, step, mode, input: &[u32]| {
+        let mut statuses = vec![
+            LayerStatus::from_input(input, &vec![1.0; input.len()]),
+            LayerStatus::default(),
+        ];
+        layer.query_active_node_and_compute_activations(&mut statuses, &[], 1.0, 0);
+        statuses[1].deltas.iter_mut().for_each(|d| *d = 1.0);
+        layer.back_propagate(&mut statuses);
+        layer.mark_touched(&statuses);
+        adam.begin_step(step);
+        layer.update_weights(adam, step, mode);
+    };
+
+    for &mode in &[UpdateMode::Dense, UpdateMode::Sparse, UpdateMode::Lazy] {
+        let mut layer = Layer::<WtaHash>::new(&config, 8, 0);
+        let mut adam = Adam::new(0.1);
+        let untouched = column(&layer, 5);
+        step(&mut layer, &mut adam, 0, mode, &[0, 1]);
+        let first = column(&layer, 0);
+        step(&mut layer, &mut adam, 1, mode, &[2]);
+        // Only the momentum of the first step moves input 0 in the second.
+        assert_eq!(column(&layer, 0) == first, mode == UpdateMode::Sparse);
+        assert_eq!(column(&layer, 5), untouched);
+    }
+
+    // Lazy updates of nodes first touched after the first step, or after the
+    // resume step of a layer read back without state, match dense ones: the
+    // state is zero until then, so only the weight decay has to catch up.
+    let touch = |layer: &mut Layer<WtaHash>, adamw: &mut AdamW, step, mode, nodes: &[usize]| {
+        let mut statuses = vec![
+            LayerStatus::from_input(&[0, 1], &[1.0, 1.0]),
+            LayerStatus {
+                active_nodes: nodes.to_vec(),
+                active_values: vec![1.0; nodes.len()],
+                pre_activations: Vec::new(),
+                deltas: vec![1.0; nodes.len()],
+            },
+        ];
+        layer.back_propagate_pre_activations(&mut statuses);
+        layer.mark_touched(&statuses);
+        adamw.begin_step(step);
+        layer.update_weights(adamw, step, mode);
+    };
+    for &start in &[0, 3] {
+        let mut layers = [UpdateMode::Dense, UpdateMode::Lazy].iter().map(|&mode| {
+            let mut layer = Layer::<WtaHash>::new(&config, 8, 0);
+            if start > 0 {
+                let mut bytes = Vec::new();
+                layer.write_to(&mut bytes, 0).unwrap();
+                layer = Layer::read_from(&mut bytes.as_slice(), 8, 0).unwrap();
+            }
+            let mut adamw = AdamW::new(0.1, 0.1);
+            for (step, nodes) in (start..).zip(&[&[][..], &[0], &[0, 1], &[0, 1, 2, 3]]) {
+                touch(&mut layer, &mut adamw, step, mode, nodes);
+            }
+            layer
+        });
+        let (dense, lazy) = (layers.next().unwrap(), layers.next().unwrap());
+        for id in 0..dense.size() {
+            let (dense, lazy) = (dense.node(id), lazy.node(id));
+            assert!((dense.bias - lazy.bias).abs() < 1e-5);
+            for (a, b) in dense.weights.iter().zip(lazy.weights) {
+                assert!((a - b).abs() < 1e-5, "{} vs {}", a, b);
+            }
+        }
+    }
+
+    // The derivatives where they are known, which only read the
+    // pre-activations of the node types keeping them. `test_gradients` checks
+    // them all against finite differences.
+    let derivative = |node_type: NodeType, x: f32| {
+        let y = node_type.activation(x);
+        let x = if node_type.keeps_pre_activations() {
+            x
+        } else {
+            f32::NAN
+        };
+        node_type.derivative(x, y)
+    };
+    assert_eq!(derivative(NodeType::Relu, -1.0), 0.0);
+    assert_eq!(derivative(NodeType::Relu, 2.0), 1.0);
+    assert_eq!(derivative(NodeType::LeakyRelu(0.1), -1.0), 0.1);
+    assert_eq!(derivative(NodeType::Linear, 3.0), 1.0);
+    assert_eq!(derivative(NodeType::Sigmoid, 0.0), 0.25);
+    assert_eq!(derivative(NodeType::Tanh, 0.0), 1.0);
+    assert_eq!(derivative(NodeType::Gelu, 0.0), 0.5);
+
+    // Probing more buckets only adds nodes.
+    let config = LayerConfig {
+        size: 200,
+        k: 3,
+        l: 2,
+        range_pow: 9,
+        sparsity: 0.1,
+        ..config
+    };
+    let mut layer = Layer::<WtaHash>::new(&config, 20, 0);
+    layer.min_active_nodes = 0;
+    let mut active_nodes = Vec::new();
+    for &probes_per_table in &[1, 4, 16] {
+        layer.probes_per_table = probes_per_table;
+        let mut statuses = vec![
+            LayerStatus::from_input(&[1, 4, 9, 16], &[1.0, 0.5, -0.5, 2.0]),
+            LayerStatus::default(),
+        ];
+        layer.query_active_node_and_compute_activations(&mut statuses, &[], 0.1, 0);
+        let mut nodes = statuses[1].active_nodes.clone();
+        nodes.sort_unstable();
+        assert!(active_nodes.iter().all(|id| nodes.contains(id)));
+        assert!(active_nodes.len() <= nodes.len());
+        active_nodes = nodes;
+    }
+
+    // Each sampling mode keeps the nodes its rule picks out of the buckets
+    // the input falls into.
+    let config = LayerConfig {
+        l: 8,
+        range_pow: 3,
+        bucket_size: 200,
+        ..config
+    };
+    let mut layer = Layer::<WtaHash>::new(&config, 20, 0);
+    layer.min_active_nodes = 0;
+    let (indices, values) = ([1, 4, 9, 16], [1.0, 0.5, -0.5, 2.0]);
+    let nodes: Vec<usize> = indices.iter().map(|&i| i as usize).collect();
+    let hashes = layer.hasher.hash_sparse(&values, &nodes);
+    let hash_indices: Vec<_> = layer
+        .hash_tables
+        .hashes_to_indices(&layer.hasher, &hashes)
+        .into_iter()
+        .map(|i| vec![i])
+        .collect();
+    let buckets: Vec<Vec<u32>> = (0..config.l)
+        .map(|i| layer.hash_tables.get_bucket(i, hash_indices[i][0]).to_vec())
+        .collect();
+    let counts = layer.count_collisions(&hash_indices);
+    let target = 50;
+    let mut query = |sampling_mode, seed| {
+        layer.sampling_mode = sampling_mode;
+        let mut statuses = vec![
+            LayerStatus::from_input(&indices, &values),
+            LayerStatus::default(),
+        ];
+        layer.query_active_node_and_compute_activations(&mut statuses, &[], 0.25, seed);
+        statuses[1].active_nodes.clone()
+    };
+
+    // Vanilla adds whole buckets, from a random table on, until the target
+    // is reached.
+    let vanilla = |offset: usize| -> Vec<usize> {
+        let mut nodes = std::collections::BTreeSet::new();
+        for i in 0..config.l {
+            if nodes.len() >= target {
+                break;
             }
+            let bucket = &buckets[(i + offset) % config.l];
+            nodes.extend(bucket.iter().map(|&id| id as usize));
         }
+        nodes.into_iter().collect()
+    };
+    for seed in 0..8 {
+        let nodes = query(SamplingMode::Vanilla, seed);
+        assert!(nodes.len() >= target && nodes.len() < counts.len());
+        assert!((0..config.l).any(|offset| vanilla(offset) == nodes));
     }
+
+    // TopK keeps the nodes colliding most.
+    let nodes = query(SamplingMode::TopK, 0);
+    assert_eq!(nodes.len(), target);
+    let count = |id: &usize| counts[&(*id as u32)];
+    let least_kept = nodes.iter().map(count).min().unwrap();
+    assert!(counts
+        .iter()
+        .filter(|(id, _)| !nodes.contains(&(**id as usize)))
+        .all(|(_, &c)| c <= least_kept));
+
+    // Threshold drops the nodes colliding in fewer tables.
+    let nodes = query(SamplingMode::Threshold(3), 0);
+    let mut expected: Vec<_> = counts
+        .iter()
+        .filter(|(_, &c)| c >= 3)
+        .map(|(&id, _)| id as usize)
+        .collect();
+    expected.sort_unstable();
+    assert_eq!(nodes, expected);
+    assert!(!nodes.is_empty() && nodes.len() < counts.len());
 }
@@ -1,13 +1,44 @@
-pub mod adam;
+pub mod batch;
 pub mod bucket;
-// pub mod densified_min_hash;
+pub mod checkpoint;
+pub mod dataset;
+pub mod densified_min_hash;
 pub mod densified_wta_hash;
-pub mod densified_wta_hash_org;
 pub mod hasher;
 pub mod layer;
+pub mod loss;
 pub mod lsh;
+pub mod metrics;
 pub mod network;
 pub mod node;
+pub mod optimizer;
 pub mod param;
-// pub mod sparse_random_projection;
+pub mod simd;
+pub mod sparse_random_projection;
 pub mod wta_hash;
+
+/// Mixes `a` and `b` into `seed`, for the seeds of independent random
+/// streams.
+pub(crate) fn mix_seed(seed: u64, a: u64, b: u64) -> u64 {
+    (seed ^ a)
+        .wrapping_mul(0x9e37_79b9_7f4a_7c15)
+        .wrapping_add(b)
+        .wrapping_mul(0xbf58_476d_1ce4_e5b9)
+}
+
+/// `f32::total_cmp`, which Rust 1.51 lacks: orders the floats by their bits,
+/// with the negative ones reversed, so that NaNs sort too.
+pub(crate) fn total_cmp(a: &f32, b: &f32) -> std::cmp::Ordering {
+    let key = |x: &f32| {
+        let bits = x.to_bits() as i32;
+        bits ^ (((bits >> 31) as u32) >> 1) as i32
+    };
+    key(a).cmp(&key(b))
+}
+
+/// The central difference `(f(h) - f(-h)) / 2h`, approximating the derivative
+/// of whatever `f` computes with respect to the shift it is given.
+#[cfg(test)]
+pub(crate) fn finite_difference<F: FnMut(f32) -> f32>(mut f: F, h: f32) -> f32 {
+    (f(h) - f(-h)) / (2.0 * h)
+}
@@ -0,0 +1,125 @@
+//! Losses of the output layer over its active nodes: the labels of the case
+//! are the positives, the other active nodes sampled negatives.
+
+use crate::layer::{LayerStatus, NodeType};
+
+/// Floor of the probabilities taken the logarithm of.
+const EPSILON: f32 = 1e-7;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Loss {
+    /// Cross entropy against the uniform distribution over the labels, for
+    /// softmax outputs.
+    SoftmaxCrossEntropy,
+    /// Binary cross entropy of every active node taken independently, for
+    /// sigmoid outputs of multi-label cases.
+    SigmoidBinaryCrossEntropy,
+    /// Half the squared distance to 1 for the labels and to 0 for the others.
+    SquaredError,
+}
+
+impl Loss {
+    /// Writes to the deltas of `layer_status`, the active nodes of an output
+    /// layer of `node_type`, the negative gradients of `scale` times the loss
+    /// of `labels` with respect to the pre-activations. Returns the loss.
+    pub fn compute(
+        self,
+        node_type: NodeType,
+        layer_status: &mut LayerStatus,
+        labels: &[u32],
+        scale: f32,
+    ) -> f32 {
+        let fused = matches!(
+            (self, node_type),
+            (Loss::SoftmaxCrossEntropy, NodeType::Softmax)
+                | (Loss::SigmoidBinaryCrossEntropy, NodeType::Sigmoid)
+        );
+        let mut loss = 0.0;
+        for k in 0..layer_status.size() {
+            let id = layer_status.active_nodes[k] as u32;
+            let y = layer_status.active_values[k];
+            let t = match (self, labels.contains(&id)) {
+                (Loss::SoftmaxCrossEntropy, true) => 1.0 / labels.len() as f32,
+                (_, true) => 1.0,
+                (_, false) => 0.0,
+            };
+            // The negative gradient with respect to `y`.
+            let gradient = match self {
+                Loss::SoftmaxCrossEntropy => {
+                    let y = y.max(EPSILON);
+                    loss -= t * y.ln();
+                    t / y
+                }
+                Loss::SigmoidBinaryCrossEntropy => {
+                    let y = y.clamp(EPSILON, 1.0 - EPSILON);
+                    loss -= t * y.ln() + (1.0 - t) * (1.0 - y).ln();
+                    t / y - (1.0 - t) / (1.0 - y)
+                }
+                Loss::SquaredError => {
+                    loss += 0.5 * (y - t) * (y - t);
+                    t - y
+                }
+            };
+            // The activations these losses are made for cancel the
+            // denominators out.
+            layer_status.deltas[k] = scale * if fused { t - y } else { gradient };
+        }
+        if !fused {
+            node_type.back_propagate(layer_status);
+        }
+        loss
+    }
+}
+
+#[test]
+fn test() {
+    // The outputs of six active nodes, two of them labels. `test_gradients`
+    // in the network checks the deltas of every loss and node type against
+    // finite differences.
+    let labels = [8, 1];
+    let active_nodes = vec![3, 0, 8, 5, 1, 2];
+    let outputs = [0.1f32, 0.05, 0.4, 0.15, 0.2, 0.1];
+    let targets = [0.0f32, 0.0, 1.0, 0.0, 1.0, 0.0];
+    let compute = |loss: Loss, node_type: NodeType| {
+        let mut layer_status = LayerStatus {
+            active_nodes: active_nodes.clone(),
+            active_values: outputs.to_vec(),
+            pre_activations: outputs.to_vec(),
+            deltas: vec![0.0; outputs.len()],
+        };
+        let value = loss.compute(node_type, &mut layer_status, &labels, 0.5);
+        (value, layer_status.deltas)
+    };
+    let close = |a: f32, b: f32| (a - b).abs() < 1e-6;
+
+    // Softmax cross entropy is the plain one against half of each label.
+    let (value, deltas) = compute(Loss::SoftmaxCrossEntropy, NodeType::Softmax);
+    assert!(close(value, -0.5 * (0.4f32.ln() + 0.2f32.ln())));
+    // With the activations they are made for, and the identity for the
+    // squared error, the deltas are the scaled distances to the targets.
+    for k in 0..outputs.len() {
+        assert!(close(deltas[k], 0.5 * (targets[k] / 2.0 - outputs[k])));
+    }
+
+    let (value, deltas) = compute(Loss::SigmoidBinaryCrossEntropy, NodeType::Sigmoid);
+    let expected: f32 = outputs
+        .iter()
+        .zip(&targets)
+        .map(|(y, t)| -(t * y.ln() + (1.0 - t) * (1.0 - y).ln()))
+        .sum();
+    assert!(close(value, expected));
+    for k in 0..outputs.len() {
+        assert!(close(deltas[k], 0.5 * (targets[k] - outputs[k])));
+    }
+
+    let (value, deltas) = compute(Loss::SquaredError, NodeType::Linear);
+    let expected: f32 = outputs
+        .iter()
+        .zip(&targets)
+        .map(|(y, t)| 0.5 * (y - t) * (y - t))
+        .sum();
+    assert!(close(value, expected));
+    for k in 0..outputs.len() {
+        assert!(close(deltas[k], 0.5 * (targets[k] - outputs[k])));
+    }
+}
@@ -1,28 +1,69 @@
-use crate::{bucket::Bucket, hasher::Hasher};
+use std::io::{self, Read, Write};
+
+use rand::{rngs::StdRng, SeedableRng};
+use rayon::prelude::*;
+
+use crate::{
+    bucket::{Bucket, BucketPolicy},
+    checkpoint::*,
+    hasher::{Hasher, Probe},
+};
+
+/// Bucket indices are saved as `u32`, which bounds the number of buckets of a
+/// table.
+pub const MAX_RANGE_POW: usize = 32;
 
 pub struct Lsh {
     bucket: Vec<Vec<Bucket>>,
+    // One per table, for the reservoir policy, seeded anew by every `clear`
+    // so that the tables only depend on `seed`, the number of clears and the
+    // ids added since.
+    rngs: Vec<StdRng>,
+    seed: u64,
+    clears: u64,
     k: usize,
     l: usize,
     range_pow: usize,
+    bucket_size: usize,
+    bucket_policy: BucketPolicy,
 }
 
 impl Lsh {
-    pub fn new(k: usize, l: usize, range_pow: usize) -> Self {
+    pub fn new(
+        k: usize,
+        l: usize,
+        range_pow: usize,
+        bucket_size: usize,
+        bucket_policy: BucketPolicy,
+        seed: u64,
+    ) -> Self {
         let mut bucket = Vec::with_capacity(l);
         for i in 0..l {
             bucket.push(Vec::with_capacity(1 << range_pow));
             for _ in 0..1 << range_pow {
-                bucket[i].push(Bucket::new());
+                bucket[i].push(Bucket::default());
             }
         }
 
-        Self {
+        let mut lsh = Self {
             bucket,
+            rngs: Vec::new(),
+            seed,
+            clears: 0,
             k,
             l,
             range_pow,
-        }
+            bucket_size,
+            bucket_policy,
+        };
+        lsh.seed_rngs();
+        lsh
+    }
+
+    fn seed_rngs(&mut self) {
+        self.rngs = (0..self.l as u64)
+            .map(|i| StdRng::seed_from_u64(crate::mix_seed(self.seed, self.clears, i)))
+            .collect();
     }
 
     pub fn clear(&mut self) {
@@ -31,25 +72,149 @@ impl Lsh {
                 bucket.clear();
             }
         }
+        self.clears += 1;
+        self.seed_rngs();
+    }
+
+    /// Writes the number of clears and the contents of the nonempty buckets.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_u64(w, self.clears)?;
+        for buckets in &self.bucket {
+            let nonempty: Vec<_> = (0..buckets.len())
+                .filter(|&j| buckets[j].get_size() > 0)
+                .collect();
+            write_usize(w, nonempty.len())?;
+            for j in nonempty {
+                write_u32(w, j as u32)?;
+                buckets[j].write_to(w)?;
+            }
+        }
+        Ok(())
     }
 
-    pub fn hashes_to_indices<H: Hasher>(&self, hashes: &[usize]) -> Vec<usize> {
-        H::hashes_to_indices(hashes, self.k, self.l, self.range_pow)
+    /// Restores the tables written by `write_to` into empty tables of the
+    /// same shape, checking the ids are below `number_of_ids`.
+    pub fn read_from<R: Read>(&mut self, r: &mut R, number_of_ids: usize) -> io::Result<()> {
+        self.clears = read_u64(r)?;
+        self.seed_rngs();
+        for buckets in &mut self.bucket {
+            for _ in 0..read_usize(r)? {
+                let bucket = buckets
+                    .get_mut(read_u32(r)? as usize)
+                    .ok_or_else(|| invalid_data("corrupted hash table"))?;
+                bucket.read_from(r, self.bucket_size, number_of_ids)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn hashes_to_indices<H: Hasher>(&self, hasher: &H, hashes: &[usize]) -> Vec<usize> {
+        hasher.hashes_to_indices(hashes, self.k, self.l, self.range_pow)
+    }
+
+    /// The buckets to query in every table for `probes`: the bucket of their
+    /// hashes, followed by up to `probes_per_table - 1` buckets where some
+    /// hashes of the table are switched to their runners-up, the smallest
+    /// total margin first.
+    pub fn probe_indices<H: Hasher>(
+        &self,
+        hasher: &H,
+        probes: &[Probe],
+        probes_per_table: usize,
+    ) -> Vec<Vec<usize>> {
+        let index =
+            |hashes: &[usize]| hasher.hashes_to_indices(hashes, self.k, 1, self.range_pow)[0];
+        probes
+            .chunks(self.k)
+            .take(self.l)
+            .map(|probes| {
+                let mut hashes: Vec<usize> = probes.iter().map(|p| p.hash).collect();
+                let mut indices = vec![index(&hashes)];
+
+                let mut order: Vec<usize> = (0..self.k)
+                    .filter(|&j| probes[j].margin.is_finite())
+                    .collect();
+                order.sort_by(|&a, &b| crate::total_cmp(&probes[a].margin, &probes[b].margin));
+                let margin =
+                    |set: &[usize]| -> f32 { set.iter().map(|&j| probes[order[j]].margin).sum() };
+
+                // Sets of positions in `order` to switch. Every set is reached
+                // once from {0} by shifting its last position by one or
+                // appending the next one, neither of which lowers its margin.
+                let mut candidates = Vec::new();
+                if !order.is_empty() {
+                    candidates.push((margin(&[0]), vec![0]));
+                }
+                while indices.len() < probes_per_table && !candidates.is_empty() {
+                    let best = (0..candidates.len())
+                        .min_by(|&a, &b| crate::total_cmp(&candidates[a].0, &candidates[b].0))
+                        .unwrap();
+                    let (_, set) = candidates.swap_remove(best);
+                    let last = *set.last().unwrap();
+                    if last + 1 < order.len() {
+                        let mut shifted = set.clone();
+                        *shifted.last_mut().unwrap() = last + 1;
+                        candidates.push((margin(&shifted), shifted));
+                        let mut expanded = set.clone();
+                        expanded.push(last + 1);
+                        candidates.push((margin(&expanded), expanded));
+                    }
+
+                    for &j in &set {
+                        hashes[order[j]] = probes[order[j]].runner_up;
+                    }
+                    let probe = index(&hashes);
+                    if !indices.contains(&probe) {
+                        indices.push(probe);
+                    }
+                    for &j in &set {
+                        hashes[order[j]] = probes[order[j]].hash;
+                    }
+                }
+                indices
+            })
+            .collect()
     }
 
     pub fn add(&mut self, indices: &[usize], id: u32) {
-        for i in 0..self.l {
-            self.bucket[i][indices[i]].add(id);
+        for ((buckets, rng), &index) in self.bucket.iter_mut().zip(&mut self.rngs).zip(indices) {
+            buckets[index].add(id, self.bucket_size, self.bucket_policy, rng);
         }
     }
 
-    pub fn get_ids(&self, indices: &[usize]) -> Vec<u32> {
+    /// Adds the nodes `first_id..first_id + hash_indices.len()`, where
+    /// `hash_indices[i]` are the bucket indices of node `first_id + i`.
+    ///
+    /// The tables are filled in parallel, each one in node order.
+    pub fn add_batch(&mut self, hash_indices: &[Vec<usize>], first_id: u32) {
+        let (bucket_size, bucket_policy) = (self.bucket_size, self.bucket_policy);
+        self.bucket
+            .par_iter_mut()
+            .zip(&mut self.rngs)
+            .enumerate()
+            .for_each(|(i, (buckets, rng))| {
+                for (j, indices) in hash_indices.iter().enumerate() {
+                    buckets[indices[i]].add(first_id + j as u32, bucket_size, bucket_policy, rng);
+                }
+            });
+    }
+
+    /// The nodes in the buckets `indices[i]` of every table `i`.
+    pub fn get_ids(&self, indices: &[Vec<usize>]) -> Vec<u32> {
         (0..self.l)
-            .flat_map(|i| self.bucket[i][indices[i]].get_all())
+            .flat_map(|i| {
+                indices[i]
+                    .iter()
+                    .flat_map(move |&j| self.bucket[i][j].get_all())
+            })
             .cloned()
             .collect()
     }
 
+    pub fn get_bucket(&self, table: usize, index: usize) -> &[u32] {
+        self.bucket[table][index].get_all()
+    }
+
     #[allow(dead_code)]
     pub fn print_count(&self) {
         for i in 0..self.l {
@@ -66,3 +231,52 @@ impl Lsh {
         }
     }
 }
+
+#[test]
+fn test() {
+    let hash_indices: Vec<Vec<usize>> = (0..300).map(|i| vec![i % 4, i % 7, 3]).collect();
+
+    let mut lsh = Lsh::new(1, 3, 3, 16, BucketPolicy::Reservoir, 7);
+    lsh.add_batch(&hash_indices[..200], 0);
+    lsh.add_batch(&hash_indices[200..], 200);
+
+    let mut expected = Lsh::new(1, 3, 3, 16, BucketPolicy::Reservoir, 7);
+    for (i, indices) in hash_indices.iter().enumerate() {
+        expected.add(indices, i as u32);
+    }
+
+    for table in 0..3 {
+        for index in 0..1 << 3 {
+            assert_eq!(
+                lsh.get_bucket(table, index),
+                expected.get_bucket(table, index)
+            );
+        }
+    }
+
+    // Two hashes in bins of 3 make index h0 + 3 * h1; the second is switched
+    // first, then the first, then both.
+    use crate::{hasher::HasherConfig, wta_hash::WtaHash};
+    let config = HasherConfig {
+        bin_size: 3,
+        ..HasherConfig::default()
+    };
+    let hasher = WtaHash::new(4, 3, &config, &mut StdRng::seed_from_u64(0));
+    let lsh = Lsh::new(2, 2, 4, 16, BucketPolicy::Fifo, 0);
+    let probe = |hash, runner_up, margin| Probe {
+        hash,
+        runner_up,
+        margin,
+    };
+    let probes = [
+        probe(0, 1, 0.5),
+        probe(2, 0, 0.1),
+        probe(1, 1, f32::INFINITY),
+        probe(2, 0, 0.3),
+    ];
+    assert_eq!(
+        lsh.probe_indices(&hasher, &probes, 4),
+        [vec![6, 0, 7, 1], vec![7, 1]]
+    );
+    assert_eq!(lsh.probe_indices(&hasher, &probes, 1), [vec![6], vec![7]]);
+}
@@ -1,17 +1,33 @@
-use slide::densified_wta_hash_org::DensifiedWtaHash;
-use slide::network::{Case, LayerConfig, Network};
-use slide::layer::NodeType;
+use slide::bucket::BucketPolicy;
+use slide::dataset::Dataset;
+use slide::densified_wta_hash::DensifiedWtaHash;
+use slide::hasher::HasherConfig;
+use slide::layer::{NodeType, SamplingMode};
+use slide::metrics::{inverse_propensities, Metrics};
+use slide::network::{LayerConfig, Network, UpdateMode};
+use slide::optimizer::Adam;
+use std::error::Error;
 
 const BATCH_SIZE: usize = 128;
 const CASE_PER_REHASH: usize = 6400;
 const CASE_PER_REBUILD: usize = 128000;
 const STEP_SIZE: usize = 1000;
+const SEED: u64 = 0;
 const TRAIN_FILE: &str = "../Amazon/amazon_train.txt";
 const TEST_FILE: &str = "../Amazon/amazon_test.txt";
+const CHECKPOINT_FILE: &str = "amazon.slide";
 
-fn main() {
-    let learning_rate = 0.001;
-    let input_size = 135909;
+fn main() -> Result<(), Box<dyn Error>> {
+    let optimizer = Adam::new(0.001);
+
+    let start = std::time::Instant::now();
+    let mut train_data = Dataset::load(TRAIN_FILE)?;
+    let test_data = Dataset::load(TEST_FILE)?;
+    println!("dataset loaded elapsed: {:?}", start.elapsed());
+
+    let header = *train_data.header();
+    let inv_propensities =
+        inverse_propensities(&train_data.label_counts(), train_data.len(), 0.6, 2.6);
 
     let layers = [
         LayerConfig {
@@ -21,88 +37,83 @@ fn main() {
             l: 20,
             range_pow: 6,
             sparsity: 1.0,
+            sampling_mode: SamplingMode::Union,
+            bucket_size: 128,
+            bucket_policy: BucketPolicy::Fifo,
+            probes_per_table: 1,
+            hasher: HasherConfig::default(),
         },
         LayerConfig {
-            size: 670091,
+            size: header.num_labels,
             node_type: NodeType::Softmax,
             k: 6,
             l: 50,
             range_pow: 18,
             sparsity: 0.005,
+            sampling_mode: SamplingMode::Union,
+            bucket_size: 128,
+            bucket_policy: BucketPolicy::Fifo,
+            probes_per_table: 1,
+            hasher: HasherConfig::default(),
         },
     ];
 
     let start = std::time::Instant::now();
     let mut network =
-        Network::<DensifiedWtaHash>::new(BATCH_SIZE, learning_rate, input_size, &layers);
+        Network::<DensifiedWtaHash>::new(BATCH_SIZE, optimizer, header.num_features, &layers, SEED);
+    network.set_update_mode(UpdateMode::Sparse);
     println!("network built elapsed: {:?}", start.elapsed());
 
+    let num_batches = train_data.num_batches(BATCH_SIZE);
     for epoch in 0..10 {
         println!("epoch {}", epoch);
-        train(490449 / BATCH_SIZE, &mut network, epoch);
-        test(100, &mut network, (epoch + 1) * (490449 / BATCH_SIZE));
-    }
-}
-
-fn train(num_batches: usize, network: &mut Network<DensifiedWtaHash>, epoch: usize) {
-    use std::io::prelude::*;
-    let file = std::fs::File::open(TRAIN_FILE).unwrap();
-    let reader = std::io::BufReader::new(file);
-    let mut lines = reader.lines().skip(1);
-    for i in 0..num_batches {
-        if (i + epoch * num_batches) % STEP_SIZE == 0 {
-            test(20, network, epoch * num_batches + i);
-        }
-        let mut cases = Vec::new();
-        while let Some(Ok(str)) = lines.next() {
-            let a = str.split(' ').skip(1).map(|s| s.split(':').collect::<Vec<_>>()).collect::<Vec<_>>();
-            let b = str.split(' ').next().unwrap().split(',').collect::<Vec<_>>();
-
-            cases.push(Case {
-                indices: a.iter().map(|s| s[0].parse::<usize>().unwrap()).collect(),
-                values: a.iter().map(|s| s[1].parse::<f32>().unwrap()).collect(),
-                labels: b.iter().map(|s| s.parse::<u32>().unwrap()).collect(),
-            });
-            if cases.len() == BATCH_SIZE {
-                break;
+        train_data.shuffle(SEED + epoch as u64);
+        for (i, cases) in train_data.batches(BATCH_SIZE).enumerate() {
+            let iter = epoch * num_batches + i;
+            if iter % STEP_SIZE == 0 {
+                test(20, &mut network, iter, &test_data, &inv_propensities);
             }
-        }
 
-        let iter = epoch * num_batches + i;
-        let rehash = iter % (CASE_PER_REHASH / BATCH_SIZE) == CASE_PER_REHASH / BATCH_SIZE - 1;
-        let rebuild = iter % (CASE_PER_REBUILD / BATCH_SIZE) == CASE_PER_REBUILD / BATCH_SIZE - 1;
-        network.train(&cases, iter, rehash, rebuild);
-        if i % 20 == 0 {
-            println!("epoch {}, training {}% done.", epoch, 100.0 * i as f32 / num_batches as f32);
+            let rehash = iter % (CASE_PER_REHASH / BATCH_SIZE) == CASE_PER_REHASH / BATCH_SIZE - 1;
+            let rebuild =
+                iter % (CASE_PER_REBUILD / BATCH_SIZE) == CASE_PER_REBUILD / BATCH_SIZE - 1;
+            let stats = network.train(&cases, iter, rehash, rebuild);
+            if i % 20 == 0 {
+                println!(
+                    "epoch {}, training {}% done, {}",
+                    epoch,
+                    100.0 * i as f32 / num_batches as f32,
+                    stats
+                );
+            }
         }
+        let iter = (epoch + 1) * num_batches;
+        test(
+            test_data.num_batches(BATCH_SIZE),
+            &mut network,
+            iter,
+            &test_data,
+            &inv_propensities,
+        );
+        network.save(CHECKPOINT_FILE, true)?;
     }
+    Ok(())
 }
 
-fn test(num_batches: usize, network: &mut Network<DensifiedWtaHash>, iter: usize) {
-    use std::io::prelude::*;
-    let file = std::fs::File::open(TEST_FILE).unwrap();
-    let reader = std::io::BufReader::new(file);
-    let mut lines = reader.lines().skip(1);
-    let mut correct_pred_sum = 0;
-    for i in 0..num_batches {
-        let mut cases = Vec::new();
-        while let Some(Ok(str)) = lines.next() {
-            let a = str.split(' ').skip(1).map(|s| s.split(':').collect::<Vec<_>>()).collect::<Vec<_>>();
-            let b = str.split(' ').next().unwrap().split(',').collect::<Vec<_>>();
-
-            cases.push(Case {
-                indices: a.iter().map(|s| s[0].parse::<usize>().unwrap()).collect(),
-                values: a.iter().map(|s| s[1].parse::<f32>().unwrap()).collect(),
-                labels: b.iter().map(|s| s.parse::<u32>().unwrap()).collect(),
-            });
-            if cases.len() == BATCH_SIZE {
-                break;
-            }
+fn test(
+    num_batches: usize,
+    network: &mut Network<DensifiedWtaHash>,
+    iter: usize,
+    test_data: &Dataset,
+    inv_propensities: &[f32],
+) {
+    let mut metrics = Metrics::with_propensities(5, inv_propensities.to_vec());
+    for (i, cases) in test_data.batches(BATCH_SIZE).take(num_batches).enumerate() {
+        let predictions = network.predict_top_k_batch(&cases, 5);
+        metrics.add_batch(&predictions, &cases);
+        if i % 100 == 0 {
+            println!("test {} {}", i, metrics);
         }
-
-        let correct_pred = network.test(&cases);
-        println!("test {} correct {}/{}", i, correct_pred, cases.len());
-        correct_pred_sum += correct_pred;
     }
-    println!("iter {}, test finished correct {}%", iter, 100.0 * correct_pred_sum as f32 / (BATCH_SIZE * num_batches) as f32);
+    println!("iter {}, test finished {}", iter, metrics);
 }
@@ -0,0 +1,203 @@
+//! Ranking metrics of extreme multi-label classification, as reported on the
+//! Extreme Classification Repository.
+
+use std::fmt;
+
+use crate::batch::Batch;
+
+/// Inverse propensity scores `1 / p_l` of the labels, following Jain et al.,
+/// "Extreme Multi-label Loss Functions for Recommendation, Tagging, Ranking
+/// & Other Missing Label Applications", KDD 2016:
+///
+/// `p_l = 1 / (1 + C (N_l + B)^-A)` with `C = (ln N - 1) (B + 1)^A`,
+///
+/// where `N_l` is the number of training cases of label `l` and `N` the
+/// number of training cases. The paper uses `A = 0.6, B = 2.6` for Amazon
+/// and `A = 0.55, B = 1.5` for most other datasets.
+pub fn inverse_propensities(label_counts: &[usize], num_cases: usize, a: f32, b: f32) -> Vec<f32> {
+    let c = ((num_cases as f32).ln() - 1.0) * (b + 1.0).powf(a);
+    label_counts
+        .iter()
+        .map(|&count| 1.0 + c * (count as f32 + b).powf(-a))
+        .collect()
+}
+
+/// Precision@k, nDCG@k and, given inverse propensities, PSP@k for every `k`
+/// up to `max_k`, averaged over the cases added.
+pub struct Metrics {
+    max_k: usize,
+    inv_propensities: Option<Vec<f32>>,
+    count: usize,
+    precision: Vec<f64>,
+    ndcg: Vec<f64>,
+    psp: Vec<f64>,
+    psp_best: Vec<f64>,
+}
+
+impl Metrics {
+    pub fn new(max_k: usize) -> Self {
+        Metrics {
+            max_k,
+            inv_propensities: None,
+            count: 0,
+            precision: vec![0.0; max_k],
+            ndcg: vec![0.0; max_k],
+            psp: vec![0.0; max_k],
+            psp_best: vec![0.0; max_k],
+        }
+    }
+
+    /// Also computes PSP@k, see `inverse_propensities`.
+    pub fn with_propensities(max_k: usize, inv_propensities: Vec<f32>) -> Self {
+        Metrics {
+            inv_propensities: Some(inv_propensities),
+            ..Self::new(max_k)
+        }
+    }
+
+    /// Adds a case given its predictions, best first as returned by
+    /// `Network::predict_top_k`, and its true labels.
+    pub fn add(&mut self, predictions: &[(u32, f32)], labels: &[u32]) {
+        self.count += 1;
+
+        let mut hits = 0.0;
+        let mut dcg = 0.0;
+        let mut ideal_dcg = 0.0;
+        for k in 0..self.max_k {
+            let discount = 1.0 / (k as f64 + 2.0).log2();
+            if let Some((label, _)) = predictions.get(k) {
+                if labels.contains(label) {
+                    hits += 1.0;
+                    dcg += discount;
+                }
+            }
+            if k < labels.len() {
+                ideal_dcg += discount;
+            }
+            self.precision[k] += hits / (k + 1) as f64;
+            if ideal_dcg > 0.0 {
+                self.ndcg[k] += dcg / ideal_dcg;
+            }
+        }
+
+        if let Some(inv_propensities) = &self.inv_propensities {
+            let inv_propensity = |label: u32| inv_propensities[label as usize] as f64;
+            let mut best: Vec<f64> = labels.iter().map(|&l| inv_propensity(l)).collect();
+            best.sort_unstable_by(|a, b| b.partial_cmp(a).unwrap());
+
+            let mut psp = 0.0;
+            let mut psp_best = 0.0;
+            for k in 0..self.max_k {
+                if let Some(&(label, _)) = predictions.get(k) {
+                    if labels.contains(&label) {
+                        psp += inv_propensity(label);
+                    }
+                }
+                psp_best += best.get(k).unwrap_or(&0.0);
+                self.psp[k] += psp / (k + 1) as f64;
+                self.psp_best[k] += psp_best / (k + 1) as f64;
+            }
+        }
+    }
+
+    pub fn add_batch(&mut self, predictions: &[Vec<(u32, f32)>], cases: &Batch) {
+        for (predictions, case) in predictions.iter().zip(cases.iter()) {
+            self.add(predictions, case.labels);
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Precision@k, 0 if no case was added. Panics unless `k` is in
+    /// `1..=max_k`, as do `ndcg` and `psp`.
+    pub fn precision(&self, k: usize) -> f32 {
+        Self::ratio(self.precision[self.index(k)], self.count as f64)
+    }
+
+    pub fn ndcg(&self, k: usize) -> f32 {
+        Self::ratio(self.ndcg[self.index(k)], self.count as f64)
+    }
+
+    /// PSP@k normalized by the best PSP@k achievable on the cases added, as
+    /// on the Extreme Classification Repository, 0 if that best is 0. `None`
+    /// without propensities.
+    pub fn psp(&self, k: usize) -> Option<f32> {
+        let i = self.index(k);
+        self.inv_propensities
+            .as_ref()
+            .map(|_| Self::ratio(self.psp[i], self.psp_best[i]))
+    }
+
+    fn index(&self, k: usize) -> usize {
+        assert!(
+            0 < k && k <= self.max_k,
+            "k must be in 1..={}, got {}",
+            self.max_k,
+            k
+        );
+        k - 1
+    }
+
+    fn ratio(sum: f64, total: f64) -> f32 {
+        if total > 0.0 {
+            (sum / total) as f32
+        } else {
+            0.0
+        }
+    }
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ks = [1, 3, 5].iter().filter(|&&k| k <= self.max_k);
+        for &k in ks.clone() {
+            write!(f, "P@{} {:.4} ", k, self.precision(k))?;
+        }
+        for &k in ks.clone() {
+            write!(f, "nDCG@{} {:.4} ", k, self.ndcg(k))?;
+        }
+        for &k in ks {
+            if let Some(psp) = self.psp(k) {
+                write!(f, "PSP@{} {:.4} ", k, psp)?;
+            }
+        }
+        write!(f, "({} cases)", self.count)
+    }
+}
+
+#[test]
+fn test() {
+    let mut metrics = Metrics::with_propensities(3, vec![1.0, 2.0, 4.0, 8.0]);
+    metrics.add(&[(2, 0.5), (0, 0.3), (1, 0.2)], &[0, 1]);
+    metrics.add(&[(3, 0.9), (1, 0.1)], &[3]);
+
+    assert_eq!(metrics.count(), 2);
+    assert!((metrics.precision(1) - 0.5).abs() < 1e-6);
+    assert!((metrics.precision(3) - (2.0 / 3.0 + 1.0 / 3.0) / 2.0).abs() < 1e-6);
+
+    let ndcg_2 = (1.0 / 3f32.log2()) / (1.0 + 1.0 / 3f32.log2());
+    assert!((metrics.ndcg(2) - (ndcg_2 + 1.0) / 2.0).abs() < 1e-6);
+
+    // PSP@1: 0 + 8 achieved out of the best 2 + 8.
+    assert!((metrics.psp(1).unwrap() - 0.8).abs() < 1e-6);
+    assert_eq!(Metrics::new(1).psp(1), None);
+
+    // Nothing to average over, or no propensity to score.
+    let empty = Metrics::with_propensities(3, vec![1.0]);
+    assert_eq!(
+        (empty.precision(3), empty.ndcg(3), empty.psp(3)),
+        (0.0, 0.0, Some(0.0))
+    );
+    let mut unlabeled = Metrics::with_propensities(1, vec![1.0]);
+    unlabeled.add(&[(0, 1.0)], &[]);
+    assert_eq!(unlabeled.psp(1), Some(0.0));
+    assert!(std::panic::catch_unwind(|| metrics.precision(0)).is_err());
+    assert!(std::panic::catch_unwind(|| metrics.ndcg(4)).is_err());
+
+    let inv_propensities = inverse_propensities(&[0, 10, 1000], 1000, 0.55, 1.5);
+    assert!(inv_propensities[0] > inv_propensities[1]);
+    assert!(inv_propensities[1] > inv_propensities[2]);
+    assert!(inv_propensities[2] > 1.0);
+}
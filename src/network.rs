@@ -1,9 +1,21 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use rayon::prelude::*;
 
 use crate::{
-    adam::{BETA1, BETA2},
-    hasher::Hasher,
-    layer::{Layer, LayerStatus, NodeType},
+    batch::{Batch, CaseRef},
+    bucket::BucketPolicy,
+    checkpoint::*,
+    hasher::{Hasher, HasherConfig},
+    layer::{Layer, LayerStatus, NodeType, SamplingMode},
+    loss::Loss,
+    optimizer::{Adam, Optimizer},
 };
 
 pub struct LayerConfig {
@@ -13,184 +25,352 @@ pub struct LayerConfig {
     pub l: usize,
     pub range_pow: usize,
     pub sparsity: f32,
+    pub sampling_mode: SamplingMode,
+    pub bucket_size: usize,
+    pub bucket_policy: BucketPolicy,
+    /// Number of buckets queried in every table, see `Lsh::probe_indices`.
+    pub probes_per_table: usize,
+    pub hasher: HasherConfig,
+}
+
+/// How the gradients of a batch are accumulated.
+#[derive(Clone, Copy)]
+pub enum GradientMode {
+    /// Cases are back-propagated concurrently into the shared parameters,
+    /// HOGWILD!-style but with lock-free atomic additions, so that none is
+    /// lost; the summation order still changes from run to run.
+    Hogwild,
+    /// Forward passes run concurrently, back-propagation runs case by case in
+    /// batch order, so that a batch always yields the same gradients.
+    Deterministic,
+}
+
+/// Which parameters are updated after a batch.
+#[derive(Clone, Copy, PartialEq)]
+pub enum UpdateMode {
+    /// Every parameter, whether it got an error or not. The default.
+    Dense,
+    /// Only the biases of the nodes active for some case of the batch and
+    /// their weights from the inputs active for some case, leaving the
+    /// optimizer state of the others untouched.
+    Sparse,
+    /// Every parameter of the nodes active for some case, after catching
+    /// their optimizer state up on the batches they were inactive for, as
+    /// if they had been updated with no error.
+    Lazy,
+}
+
+/// Statistics of a batch trained on.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TrainStats {
+    /// The loss, averaged over the cases.
+    pub loss: f32,
+    /// The number of active nodes of every layer, averaged over the cases.
+    pub active_nodes: Vec<f32>,
+    /// The fraction of the labels the LSH query of the output layer found
+    /// before they were forced active, 1 if there are no labels.
+    pub label_recall: f32,
+}
+
+impl fmt::Display for TrainStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "loss {:.4} active nodes", self.loss)?;
+        for active_nodes in &self.active_nodes {
+            write!(f, " {:.1}", active_nodes)?;
+        }
+        write!(f, " label recall {:.4}", self.label_recall)
+    }
 }
 
+/// A single case, see `Batch` for storing many.
 #[derive(Debug)]
 pub struct Case {
-    pub indices: Vec<usize>,
+    pub indices: Vec<u32>,
     pub values: Vec<f32>,
     pub labels: Vec<u32>,
 }
 
-pub struct Network<H: Hasher> {
+impl Case {
+    pub fn view(&self) -> CaseRef<'_> {
+        CaseRef {
+            indices: &self.indices,
+            values: &self.values,
+            labels: &self.labels,
+        }
+    }
+}
+
+pub struct Network<H: Hasher, O: Optimizer = Adam> {
+    input_size: usize,
     hidden_layers: Vec<Layer<H>>,
     number_of_layers: usize,
     train_statuses: Vec<Vec<LayerStatus>>,
-    learning_rate: f32,
+    optimizer: O,
+    gradient_mode: GradientMode,
+    update_mode: UpdateMode,
+    loss: Loss,
+    // The seed of every layer is drawn from it; mixed with the iteration and
+    // the index of a case, it seeds the sampling of the case.
+    seed: u64,
 }
 
-impl<H: Hasher> Network<H> {
+impl<H: Hasher, O: Optimizer> Network<H, O> {
+    /// Creates a network whose randomness all derives from `seed`: two
+    /// networks built and trained alike in `GradientMode::Deterministic` end
+    /// up identical.
     pub fn new(
         batch_size: usize,
-        learning_rate: f32,
+        optimizer: O,
         input_size: usize,
         layer_configs: &[LayerConfig],
+        seed: u64,
     ) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
         let mut hidden_layers = Vec::with_capacity(layer_configs.len());
         let mut previous_layer_size = input_size;
         for config in layer_configs {
-            hidden_layers.push(Layer::new(
-                config.size,
-                previous_layer_size,
-                config.node_type,
-                config.k,
-                config.l,
-                config.range_pow,
-                config.sparsity,
-            ));
+            hidden_layers.push(Layer::new(config, previous_layer_size, rng.gen()));
             previous_layer_size = config.size;
         }
+        Self::from_layers(batch_size, optimizer, input_size, hidden_layers, seed)
+    }
+
+    fn from_layers(
+        batch_size: usize,
+        optimizer: O,
+        input_size: usize,
+        hidden_layers: Vec<Layer<H>>,
+        seed: u64,
+    ) -> Self {
+        let number_of_layers = hidden_layers.len();
         Network {
+            input_size,
+            seed,
             hidden_layers,
-            learning_rate,
-            number_of_layers: layer_configs.len(),
+            optimizer,
+            gradient_mode: GradientMode::Hogwild,
+            update_mode: UpdateMode::Dense,
+            loss: Loss::SoftmaxCrossEntropy,
+            number_of_layers,
             train_statuses: (0..batch_size)
                 .map(|_| {
                     let mut v = Vec::new();
-                    v.resize_with(layer_configs.len() + 1, Default::default);
+                    v.resize_with(number_of_layers + 1, Default::default);
                     v
                 })
                 .collect(),
         }
     }
 
-    pub fn predict(&mut self, case: &Case) -> usize {
-        let layer_statuses = &mut self.train_statuses[0];
-        layer_statuses[0] = LayerStatus::from_input(&case.indices, &case.values);
-        // inference
-        for j in 0..self.number_of_layers {
-            self.hidden_layers[j].query_active_node_and_compute_activations(
-                &mut layer_statuses[j..j + 2],
-                &[],
-                1.0,
-            );
+    /// Saves the network to `path`, including the optimizer state of every
+    /// parameter and the hash tables if `with_state` so that training can be
+    /// resumed from the checkpoint as if uninterrupted.
+    pub fn save<P: AsRef<Path>>(&self, path: P, with_state: bool) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        self.write_to(&mut w, with_state)?;
+        w.flush()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::read_from(&mut BufReader::new(File::open(path)?))
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W, with_state: bool) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        write_u32(w, FORMAT_VERSION)?;
+        write_u8(w, with_state as u8)?;
+        write_usize(w, self.train_statuses.len())?;
+        write_u64(w, self.seed)?;
+        write_u8(w, O::TAG)?;
+        self.optimizer.write_to(w)?;
+        write_u8(
+            w,
+            match self.gradient_mode {
+                GradientMode::Hogwild => 0,
+                GradientMode::Deterministic => 1,
+            },
+        )?;
+        write_u8(
+            w,
+            match self.update_mode {
+                UpdateMode::Dense => 0,
+                UpdateMode::Sparse => 1,
+                UpdateMode::Lazy => 2,
+            },
+        )?;
+        write_u8(
+            w,
+            match self.loss {
+                Loss::SoftmaxCrossEntropy => 0,
+                Loss::SigmoidBinaryCrossEntropy => 1,
+                Loss::SquaredError => 2,
+            },
+        )?;
+        write_usize(w, self.input_size)?;
+        write_usize(w, self.number_of_layers)?;
+        let state_size = if with_state { O::STATE_SIZE } else { 0 };
+        for layer in &self.hidden_layers {
+            layer.write_to(w, state_size)?;
         }
+        Ok(())
+    }
 
-        // compute top-1
-        let mut max_act = f32::NEG_INFINITY;
-        let mut predict_class = 0;
-        let last_layer = &layer_statuses[self.number_of_layers];
-        for j in 0..last_layer.size() {
-            let act = last_layer.active_values[j];
-            if max_act < act {
-                max_act = act;
-                predict_class = last_layer.active_nodes[j];
-            }
+    /// Reads a network written by `write_to` with the same optimizer and
+    /// hasher types, failing with `InvalidData` on any other. Parameters
+    /// saved without their optimizer state start afresh.
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not a network checkpoint"));
+        }
+        if read_u32(r)? != FORMAT_VERSION {
+            return Err(invalid_data("unsupported checkpoint version"));
         }
-        predict_class
+        let state_size = if read_u8(r)? != 0 { O::STATE_SIZE } else { 0 };
+        let batch_size = read_usize(r)?;
+        let seed = read_u64(r)?;
+        if read_u8(r)? != O::TAG {
+            return Err(invalid_data("saved with another optimizer"));
+        }
+        let optimizer = O::read_from(r)?;
+        let gradient_mode = match read_u8(r)? {
+            0 => GradientMode::Hogwild,
+            1 => GradientMode::Deterministic,
+            _ => return Err(invalid_data("unknown gradient mode")),
+        };
+        let update_mode = match read_u8(r)? {
+            0 => UpdateMode::Dense,
+            1 => UpdateMode::Sparse,
+            2 => UpdateMode::Lazy,
+            _ => return Err(invalid_data("unknown update mode")),
+        };
+        let loss = match read_u8(r)? {
+            0 => Loss::SoftmaxCrossEntropy,
+            1 => Loss::SigmoidBinaryCrossEntropy,
+            2 => Loss::SquaredError,
+            _ => return Err(invalid_data("unknown loss")),
+        };
+        let input_size = read_usize(r)?;
+        let number_of_layers = read_usize(r)?;
+        let mut hidden_layers = Vec::new();
+        let mut previous_layer_size = input_size;
+        for _ in 0..number_of_layers {
+            let layer = Layer::read_from(r, previous_layer_size, state_size)?;
+            previous_layer_size = layer.size();
+            hidden_layers.push(layer);
+        }
+
+        let mut network = Self::from_layers(batch_size, optimizer, input_size, hidden_layers, seed);
+        network.gradient_mode = gradient_mode;
+        network.update_mode = update_mode;
+        network.loss = loss;
+        Ok(network)
+    }
+
+    pub fn set_gradient_mode(&mut self, gradient_mode: GradientMode) {
+        self.gradient_mode = gradient_mode;
+    }
+
+    /// Sets which parameters `train` updates, all of them by default.
+    pub fn set_update_mode(&mut self, update_mode: UpdateMode) {
+        self.update_mode = update_mode;
+    }
+
+    /// Sets the loss minimized by `train`, softmax cross entropy by default.
+    pub fn set_loss(&mut self, loss: Loss) {
+        self.loss = loss;
+    }
+
+    pub fn predict(&mut self, case: CaseRef<'_>) -> usize {
+        self.predict_top_k(case, 1)[0].0 as usize
     }
 
-    pub fn test(&mut self, cases: &[Case]) -> usize {
+    /// Returns the `k` most probable labels of `case` with their
+    /// probabilities, best first.
+    pub fn predict_top_k(&mut self, case: CaseRef<'_>, k: usize) -> Vec<(u32, f32)> {
+        let layer_statuses = &mut self.train_statuses[0];
+        Self::infer(&self.hidden_layers, layer_statuses, case);
+        Self::top_k(&layer_statuses[self.number_of_layers], k)
+    }
+
+    /// `predict_top_k` over many cases, running a batch at a time in parallel.
+    pub fn predict_top_k_batch(&mut self, cases: &Batch, k: usize) -> Vec<Vec<(u32, f32)>> {
         let hidden_layers = &self.hidden_layers;
         let number_of_layers = self.number_of_layers;
-        self.train_statuses
-            .par_iter_mut()
-            .enumerate()
-            .map(|(i, layer_statuses)| {
-                let case = &cases[i];
-                layer_statuses[0] = LayerStatus::from_input(&case.indices, &case.values);
-                // inference
-                for j in 0..number_of_layers {
-                    hidden_layers[j].query_active_node_and_compute_activations(
-                        &mut layer_statuses[j..j + 2],
-                        &[],
-                        1.0,
-                    );
-                }
+        let batch_size = self.train_statuses.len();
+        let mut predictions = Vec::with_capacity(cases.len());
+        for start in (0..cases.len()).step_by(batch_size) {
+            let end = cases.len().min(start + batch_size);
+            predictions.par_extend(self.train_statuses.par_iter_mut().zip(start..end).map(
+                |(layer_statuses, i)| {
+                    Self::infer(hidden_layers, layer_statuses, cases.get(i));
+                    Self::top_k(&layer_statuses[number_of_layers], k)
+                },
+            ));
+        }
+        predictions
+    }
 
-                // compute top-1
-                let mut max_act = f32::NEG_INFINITY;
-                let mut predict_class = 0;
-                let last_layer = &layer_statuses[number_of_layers];
-                for j in 0..last_layer.size() {
-                    let act = last_layer.active_values[j];
-                    if max_act < act {
-                        max_act = act;
-                        predict_class = last_layer.active_nodes[j];
-                    }
-                }
-                if case.labels.contains(&(predict_class as u32)) {
-                    1
-                } else {
-                    0
-                }
-            })
-            .sum()
+    pub fn test(&mut self, cases: &Batch) -> usize {
+        self.predict_top_k_batch(cases, 1)
+            .iter()
+            .zip(cases.iter())
+            .filter(|(prediction, case)| case.labels.contains(&prediction[0].0))
+            .count()
     }
 
-    pub fn train(&mut self, cases: &[Case], iter: usize, rehash: bool, rebuild: bool) {
+    fn infer(hidden_layers: &[Layer<H>], layer_statuses: &mut [LayerStatus], case: CaseRef<'_>) {
+        layer_statuses[0] = LayerStatus::from_input(case.indices, case.values);
+        for j in 0..hidden_layers.len() {
+            hidden_layers[j].query_active_node_and_compute_activations(
+                &mut layer_statuses[j..j + 2],
+                &[],
+                1.0,
+                0,
+            );
+        }
+    }
+
+    fn top_k(layer_status: &LayerStatus, k: usize) -> Vec<(u32, f32)> {
+        let order =
+            |a: &(u32, f32), b: &(u32, f32)| crate::total_cmp(&b.1, &a.1).then(a.0.cmp(&b.0));
+        let mut scores: Vec<_> = layer_status
+            .active_nodes
+            .iter()
+            .map(|&id| id as u32)
+            .zip(layer_status.active_values.iter().cloned())
+            .collect();
+        if k < scores.len() {
+            scores.select_nth_unstable_by(k, order);
+            scores.truncate(k);
+        }
+        scores.sort_unstable_by(order);
+        scores
+    }
+
+    pub fn train(&mut self, cases: &Batch, iter: usize, rehash: bool, rebuild: bool) -> TrainStats {
         let batch_size = self.train_statuses.len().min(cases.len());
         if iter % 6946 == 6945 {
             self.hidden_layers[1].random_nodes();
         }
 
-        // let start = std::time::Instant::now();
-        let hidden_layers = &self.hidden_layers;
-        let number_of_layers = self.number_of_layers;
-        self.train_statuses
-            .par_iter_mut()
-            .enumerate()
-            .for_each(|(i, layer_statuses)| {
-                let case = &cases[i];
-                layer_statuses[0] = LayerStatus::from_input(&case.indices, &case.values);
-
-                // inference
-                for j in 0..number_of_layers {
-                    let sparsity = hidden_layers[j].sparsity;
-                    let force_activate_nodes = if j == number_of_layers - 1 {
-                        case.labels.as_slice()
-                    } else {
-                        &[]
-                    };
-                    hidden_layers[j].query_active_node_and_compute_activations(
-                        &mut layer_statuses[j..j + 2],
-                        force_activate_nodes,
-                        sparsity,
-                    );
-                }
-
-                // compute loss
-                for k in 0..layer_statuses[number_of_layers].active_nodes.len() {
-                    let id = layer_statuses[number_of_layers].active_nodes[k];
-                    let activation = layer_statuses[number_of_layers].active_values[k] + 0.0000001;
-
-                    // TODO: check gradient
-                    let expect = if case.labels.contains(&(id as u32)) {
-                        1.0 / case.labels.len() as f32
-                    } else {
-                        0.0
-                    };
-                    layer_statuses[number_of_layers].deltas[k] =
-                        (expect - activation) / batch_size as f32;
-                }
+        let stats = self.accumulate_errors(cases, iter);
 
-                // backpropagate
-                for j in (0..number_of_layers).rev() {
-                    #[allow(mutable_transmutes)]
-                    let layer =
-                        unsafe { std::mem::transmute::<_, &mut Layer<H>>(&hidden_layers[j]) };
-                    layer.back_propagate(&mut layer_statuses[j..j + 2]);
+        if self.update_mode != UpdateMode::Dense {
+            for layer_statuses in &self.train_statuses[..batch_size] {
+                for (j, layer) in self.hidden_layers.iter_mut().enumerate() {
+                    layer.mark_touched(&layer_statuses[j..j + 2]);
                 }
-            });
-        // print!("step1: {:?}", start.elapsed());
+            }
+        }
 
-        let learning_rate = self.learning_rate * (1.0 - BETA2.powi(iter as i32 + 1)).sqrt()
-            / (1.0 - BETA1.powi(iter as i32 + 1));
+        self.optimizer.begin_step(iter);
 
         // update weights
         // let start = std::time::Instant::now();
         for layer in &mut self.hidden_layers {
-            layer.update_weights(learning_rate);
+            layer.update_weights(&self.optimizer, iter, self.update_mode);
             if rebuild && layer.sparsity < 1.0 {
                 layer.update_table();
             }
@@ -199,5 +379,473 @@ impl<H: Hasher> Network<H> {
             }
         }
         // println!(", step2: {:?}", start.elapsed());
+
+        stats
+    }
+
+    /// Runs the cases forward and backward, adding their errors to those of
+    /// the parameters, sampling their nodes as in iteration `iter`.
+    fn accumulate_errors(&mut self, cases: &Batch, iter: usize) -> TrainStats {
+        let batch_size = self.train_statuses.len().min(cases.len());
+
+        // let start = std::time::Instant::now();
+        let hidden_layers = &self.hidden_layers;
+        let number_of_layers = self.number_of_layers;
+        let loss = self.loss;
+        let seed = self.seed;
+        // Returns the loss of the `i`th case and the number of its labels
+        // recalled.
+        let forward = |i: usize, layer_statuses: &mut Vec<LayerStatus>| {
+            let case = cases.get(i);
+            // Whichever thread runs the case.
+            let case_seed = crate::mix_seed(seed, iter as u64, i as u64);
+            layer_statuses[0] = LayerStatus::from_input(case.indices, case.values);
+
+            // inference
+            let mut recalled = 0;
+            for j in 0..number_of_layers {
+                let sparsity = hidden_layers[j].sparsity;
+                let force_activate_nodes = if j == number_of_layers - 1 {
+                    case.labels
+                } else {
+                    &[]
+                };
+                recalled = hidden_layers[j].query_active_node_and_compute_activations(
+                    &mut layer_statuses[j..j + 2],
+                    force_activate_nodes,
+                    sparsity,
+                    case_seed,
+                );
+            }
+
+            let loss = loss.compute(
+                hidden_layers[number_of_layers - 1].node_type(),
+                &mut layer_statuses[number_of_layers],
+                case.labels,
+                1.0 / batch_size as f32,
+            );
+            (loss, recalled)
+        };
+        let backward = |layer_statuses: &mut Vec<LayerStatus>| {
+            hidden_layers[number_of_layers - 1]
+                .back_propagate_pre_activations(&mut layer_statuses[number_of_layers - 1..]);
+            for j in (0..number_of_layers - 1).rev() {
+                hidden_layers[j].back_propagate(&mut layer_statuses[j..j + 2]);
+            }
+        };
+
+        let train_statuses = &mut self.train_statuses[..batch_size];
+        let results: Vec<(f32, usize)> = match self.gradient_mode {
+            GradientMode::Hogwild => train_statuses
+                .par_iter_mut()
+                .enumerate()
+                .map(|(i, layer_statuses)| {
+                    let result = forward(i, layer_statuses);
+                    backward(layer_statuses);
+                    result
+                })
+                .collect(),
+            GradientMode::Deterministic => {
+                let results = train_statuses
+                    .par_iter_mut()
+                    .enumerate()
+                    .map(|(i, layer_statuses)| forward(i, layer_statuses))
+                    .collect();
+                train_statuses.iter_mut().for_each(backward);
+                results
+            }
+        };
+        // print!("step1: {:?}", start.elapsed());
+
+        let labels: usize = (0..batch_size).map(|i| cases.get(i).labels.len()).sum();
+        let recalled: usize = results.iter().map(|&(_, recalled)| recalled).sum();
+        TrainStats {
+            loss: results.iter().map(|&(loss, _)| loss).sum::<f32>() / batch_size.max(1) as f32,
+            active_nodes: (1..=number_of_layers)
+                .map(|j| {
+                    let active_nodes: usize = self.train_statuses[..batch_size]
+                        .iter()
+                        .map(|layer_statuses| layer_statuses[j].size())
+                        .sum();
+                    active_nodes as f32 / batch_size.max(1) as f32
+                })
+                .collect(),
+            label_recall: if labels == 0 {
+                1.0
+            } else {
+                recalled as f32 / labels as f32
+            },
+        }
+    }
+}
+
+#[test]
+fn test() {
+    use crate::{
+        densified_wta_hash::DensifiedWtaHash,
+        optimizer::{Adagrad, AdamW, Momentum},
+        wta_hash::WtaHash,
+    };
+
+    let layers = [
+        LayerConfig {
+            size: 16,
+            node_type: NodeType::Relu,
+            k: 2,
+            l: 4,
+            range_pow: 6,
+            sparsity: 1.0,
+            sampling_mode: SamplingMode::Union,
+            bucket_size: 128,
+            bucket_policy: BucketPolicy::Fifo,
+            probes_per_table: 1,
+            hasher: HasherConfig::default(),
+        },
+        LayerConfig {
+            size: 12,
+            node_type: NodeType::Softmax,
+            k: 2,
+            l: 4,
+            range_pow: 6,
+            sparsity: 0.5,
+            sampling_mode: SamplingMode::TopK,
+            bucket_size: 4,
+            bucket_policy: BucketPolicy::Reservoir,
+            probes_per_table: 1,
+            hasher: HasherConfig::default(),
+        },
+    ];
+    let cases: Vec<_> = (0..8)
+        .map(|i| Case {
+            indices: vec![i, i + 8],
+            values: vec![1.0, 0.5],
+            labels: vec![i % 12],
+        })
+        .collect();
+    let batch: Batch = cases.iter().map(Case::view).collect();
+    let mut network = Network::<WtaHash>::new(8, Adam::new(0.01), 20, &layers, 0);
+    network.set_update_mode(UpdateMode::Sparse);
+    for i in 0..3 {
+        let stats = network.train(&batch, i, i == 1, i == 2);
+        assert!(stats.loss > 0.0);
+        // The output layer is padded up to its minimum number of active
+        // nodes, past its size.
+        assert_eq!(stats.active_nodes, vec![16.0, 12.0]);
+        assert!((0.0..=1.0).contains(&stats.label_recall));
+    }
+
+    // With every node active, every label is recalled and the loss is the
+    // cross entropy of the plain forward pass.
+    let dense_layers = [
+        LayerConfig { ..layers[0] },
+        LayerConfig {
+            sparsity: 1.0,
+            ..layers[1]
+        },
+    ];
+    let mut dense = Network::<WtaHash>::new(8, Adam::new(0.01), 20, &dense_layers, 0);
+    let forward = |layer: &Layer<WtaHash>, input: &[f32]| -> Vec<f32> {
+        (0..layer.size())
+            .map(|id| {
+                let node = layer.node(id);
+                node.weights
+                    .iter()
+                    .zip(input)
+                    .map(|(w, x)| w * x)
+                    .sum::<f32>()
+                    + node.bias
+            })
+            .collect()
+    };
+    let cross_entropy = batch
+        .iter()
+        .map(|case| {
+            let mut input = vec![0.0; 20];
+            for (&i, &value) in case.indices.iter().zip(case.values) {
+                input[i as usize] = value;
+            }
+            let hidden: Vec<f32> = forward(&dense.hidden_layers[0], &input)
+                .iter()
+                .map(|&value| value.max(0.0))
+                .collect();
+            let logits = forward(&dense.hidden_layers[1], &hidden);
+            let log_sum = logits.iter().map(|logit| logit.exp()).sum::<f32>().ln();
+            log_sum - logits[case.labels[0] as usize]
+        })
+        .sum::<f32>()
+        / batch.len() as f32;
+    let stats = dense.train(&batch, 0, false, false);
+    assert!((stats.loss - cross_entropy).abs() < 1e-5);
+    assert_eq!(stats.active_nodes, vec![16.0, 12.0]);
+    assert_eq!(stats.label_recall, 1.0);
+
+    // No node collides in more than the `l` tables, so the labels are only
+    // ever forced in, never sampled.
+    let unsampled_layers = [
+        LayerConfig { ..layers[0] },
+        LayerConfig {
+            sampling_mode: SamplingMode::Threshold(5),
+            ..layers[1]
+        },
+    ];
+    let mut unsampled = Network::<WtaHash>::new(8, Adam::new(0.01), 20, &unsampled_layers, 0);
+    let stats = unsampled.train(&batch, 0, false, false);
+    assert_eq!(stats.active_nodes, vec![16.0, 12.0]);
+    assert_eq!(stats.label_recall, 0.0);
+
+    let mut checkpoint = Vec::new();
+    network.write_to(&mut checkpoint, true).unwrap();
+    let path = std::env::temp_dir().join(format!("slide-test-{}.bin", std::process::id()));
+    network.save(&path, true).unwrap();
+    let mut loaded = Network::<WtaHash>::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let mut reloaded = Vec::new();
+    loaded.write_to(&mut reloaded, true).unwrap();
+    assert_eq!(checkpoint, reloaded);
+    for case in batch.iter() {
+        assert_eq!(network.predict(case), loaded.predict(case));
+    }
+
+    assert!(Network::<WtaHash>::read_from(&mut &checkpoint[1..]).is_err());
+    // Checkpoints only load with the optimizer and hasher they were saved with.
+    assert!(Network::<WtaHash, AdamW>::read_from(&mut checkpoint.as_slice()).is_err());
+    assert!(Network::<DensifiedWtaHash>::read_from(&mut checkpoint.as_slice()).is_err());
+    let mut momentum = Vec::new();
+    Network::<WtaHash, Momentum>::new(8, Momentum::new(0.01), 20, &layers, 0)
+        .write_to(&mut momentum, false)
+        .unwrap();
+    assert!(Network::<WtaHash, Momentum>::read_from(&mut momentum.as_slice()).is_ok());
+    assert!(Network::<WtaHash, Adagrad>::read_from(&mut momentum.as_slice()).is_err());
+
+    let predictions = network.predict_top_k_batch(&batch, 3);
+    assert_eq!(predictions.len(), batch.len());
+    for (case, top_k) in batch.iter().zip(predictions) {
+        assert_eq!(network.predict_top_k(case, 3), top_k);
+        assert_eq!(top_k[0].0 as usize, network.predict(case));
+        assert!(top_k.windows(2).all(|w| w[0].1 >= w[1].1));
+    }
+    assert_eq!(network.predict_top_k(batch.get(0), 100).len(), 12);
+
+    // Runs from the same seed are identical, sampling included, even when
+    // resumed from a checkpoint.
+    let sampled_layers = [
+        LayerConfig { ..layers[0] },
+        // Past the minimum number of active nodes, which the layer pads the
+        // samples up to with random ones.
+        LayerConfig {
+            size: 1200,
+            sampling_mode: SamplingMode::Vanilla,
+            ..layers[1]
+        },
+    ];
+    let run = |seed, resume_at| {
+        let mut network = Network::<WtaHash>::new(8, Adam::new(0.01), 20, &sampled_layers, seed);
+        network.set_gradient_mode(GradientMode::Deterministic);
+        network.set_update_mode(UpdateMode::Lazy);
+        let mut stats = Vec::new();
+        // The nodes are reordered at 6945, along with a rehash and a rebuild.
+        for i in 6943..6948 {
+            if i == resume_at {
+                let mut checkpoint = Vec::new();
+                network.write_to(&mut checkpoint, true).unwrap();
+                network = Network::read_from(&mut &checkpoint[..]).unwrap();
+            }
+            stats.push(network.train(&batch, i, i % 2 == 1, i % 3 == 0));
+        }
+        let mut checkpoint = Vec::new();
+        network.write_to(&mut checkpoint, true).unwrap();
+        (stats, checkpoint)
+    };
+    let uninterrupted = run(1, 0);
+    assert_eq!(run(1, 0), uninterrupted);
+    for resume_at in 6944..6948 {
+        assert_eq!(run(1, resume_at), uninterrupted);
+    }
+    assert_ne!(run(2, 0).1, uninterrupted.1);
+
+    // Independent sigmoids learn both labels of multi-label cases.
+    let layers = [
+        LayerConfig {
+            node_type: NodeType::Tanh,
+            ..layers[0]
+        },
+        LayerConfig {
+            node_type: NodeType::Sigmoid,
+            ..layers[1]
+        },
+    ];
+    let cases: Vec<_> = (0..8)
+        .map(|i| Case {
+            indices: vec![i, i + 8],
+            values: vec![1.0, 0.5],
+            labels: vec![i % 12, (i + 4) % 12],
+        })
+        .collect();
+    let batch: Batch = cases.iter().map(Case::view).collect();
+    let mut network = Network::<WtaHash>::new(8, Adam::new(0.01), 20, &layers, 0);
+    network.set_loss(Loss::SigmoidBinaryCrossEntropy);
+    let first = network.train(&batch, 0, false, false);
+    let mut last = first.clone();
+    for i in 1..300 {
+        last = network.train(&batch, i, false, false);
+    }
+    assert!(last.loss < first.loss / 4.0);
+    for case in batch.iter() {
+        let mut top_2: Vec<_> = network.predict_top_k(case, 2).iter().map(|p| p.0).collect();
+        top_2.sort_unstable();
+        assert_eq!(top_2, case.labels);
+        assert!(network.predict_top_k(case, 3)[2].1 < 0.5);
+    }
+}
+
+#[test]
+fn test_top_k() {
+    use crate::wta_hash::WtaHash;
+
+    let layer_status = LayerStatus {
+        active_nodes: vec![5, 2, 9, 7, 3],
+        active_values: vec![0.1, 0.3, 0.3, 0.05, 0.25],
+        ..LayerStatus::default()
+    };
+    let top_k = |k| Network::<WtaHash>::top_k(&layer_status, k);
+    // Best first, ties by id.
+    assert_eq!(top_k(3), vec![(2, 0.3), (9, 0.3), (3, 0.25)]);
+    assert_eq!(top_k(1), vec![(2, 0.3)]);
+    // Only the active nodes, whatever `k`.
+    assert_eq!(
+        top_k(10),
+        vec![(2, 0.3), (9, 0.3), (3, 0.25), (5, 0.1), (7, 0.05)]
+    );
+    assert!(top_k(0).is_empty());
+}
+
+#[test]
+fn test_gradients() {
+    use crate::{optimizer::Sgd, wta_hash::WtaHash};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    type Net = Network<WtaHash, Sgd>;
+
+    // Parameter `i` of layer `j`, its biases following its weights.
+    fn param(network: &mut Net, j: usize, i: usize) -> &mut f32 {
+        let layer = &mut network.hidden_layers[j];
+        let weights = layer.weights().len();
+        if i < weights {
+            &mut layer.weights_mut().values_mut()[i]
+        } else {
+            &mut layer.biases_mut().values_mut()[i - weights]
+        }
+    }
+
+    let config = |size, node_type| LayerConfig {
+        size,
+        node_type,
+        k: 2,
+        l: 2,
+        range_pow: 4,
+        sparsity: 1.0,
+        sampling_mode: SamplingMode::Union,
+        bucket_size: 8,
+        bucket_policy: BucketPolicy::Fifo,
+        probes_per_table: 1,
+        hasher: HasherConfig {
+            bin_size: 4,
+            ..HasherConfig::default()
+        },
+    };
+    let cases = [
+        Case {
+            indices: vec![0, 2, 5],
+            values: vec![0.8, -0.5, 1.0],
+            labels: vec![1],
+        },
+        Case {
+            indices: vec![1, 2],
+            values: vec![0.3, 0.9],
+            labels: vec![0, 3],
+        },
+        Case {
+            indices: vec![3, 4, 5],
+            values: vec![-0.7, 0.4, 0.6],
+            labels: vec![2],
+        },
+    ];
+    let batch: Batch = cases.iter().map(Case::view).collect();
+
+    let node_types = [
+        NodeType::Relu,
+        NodeType::Softmax,
+        NodeType::Sigmoid,
+        NodeType::Tanh,
+        NodeType::Linear,
+        NodeType::LeakyRelu(0.1),
+        NodeType::Gelu,
+    ];
+    let mut rng = StdRng::seed_from_u64(0);
+    for &loss in &[
+        Loss::SoftmaxCrossEntropy,
+        Loss::SigmoidBinaryCrossEntropy,
+        Loss::SquaredError,
+    ] {
+        for &hidden in &node_types {
+            for &output in &node_types {
+                // The cross entropies take the outputs for probabilities.
+                if loss != Loss::SquaredError
+                    && !matches!(output, NodeType::Softmax | NodeType::Sigmoid)
+                {
+                    continue;
+                }
+                let layers = [config(5, hidden), config(4, output)];
+                let mut network = Net::new(cases.len(), Sgd::new(0.1), 6, &layers, 0);
+                network.set_gradient_mode(GradientMode::Deterministic);
+                network.set_loss(loss);
+                let sizes: Vec<_> = network
+                    .hidden_layers
+                    .iter()
+                    .map(|layer| layer.weights().len() + layer.biases().len())
+                    .collect();
+                for (j, &size) in sizes.iter().enumerate() {
+                    for i in 0..size {
+                        *param(&mut network, j, i) = rng.gen_range(-1.0..1.0);
+                    }
+                }
+
+                network.accumulate_errors(&batch, 0);
+                let errors: Vec<Vec<f32>> = network
+                    .hidden_layers
+                    .iter()
+                    .map(|layer| {
+                        let weights = (0..layer.weights().len()).map(|i| layer.weights().error(i));
+                        let biases = (0..layer.biases().len()).map(|i| layer.biases().error(i));
+                        weights.chain(biases).collect()
+                    })
+                    .collect();
+
+                for (j, errors) in errors.iter().enumerate() {
+                    for (i, &error) in errors.iter().enumerate() {
+                        let shifted_loss = |h: f32| {
+                            *param(&mut network, j, i) += h;
+                            let loss = network.accumulate_errors(&batch, 0).loss;
+                            *param(&mut network, j, i) -= h;
+                            loss
+                        };
+                        let numerical = crate::finite_difference(shifted_loss, 1e-3);
+                        // The errors are negative gradients.
+                        assert!(
+                            (numerical + error).abs() < 1e-3 + 1e-2 * numerical.abs(),
+                            "{:?}, {:?} then {:?}, layer {} parameter {}: {} vs {}",
+                            loss,
+                            hidden,
+                            output,
+                            j,
+                            i,
+                            -error,
+                            numerical
+                        );
+                    }
+                }
+            }
+        }
     }
 }
@@ -1,13 +1,28 @@
-use crate::{layer::LayerStatus, param::Param};
+use std::sync::atomic::AtomicU32;
 
-pub struct Node {
-    pub weights: Vec<Param>,
-    pub bias: Param,
+use crate::{layer::LayerStatus, param::add_error, simd};
+
+/// A node of a layer, viewing its row of the layer's parameters.
+pub struct Node<'a> {
+    pub weights: &'a [f32],
+    pub bias: f32,
+    weight_errors: &'a [AtomicU32],
+    bias_error: &'a AtomicU32,
 }
 
-impl Node {
-    pub fn new(weights: Vec<Param>, bias: Param) -> Self {
-        Self { weights, bias }
+impl<'a> Node<'a> {
+    pub fn new(
+        weights: &'a [f32],
+        bias: f32,
+        weight_errors: &'a [AtomicU32],
+        bias_error: &'a AtomicU32,
+    ) -> Self {
+        Self {
+            weights,
+            bias,
+            weight_errors,
+            bias_error,
+        }
     }
 
     pub fn get_size(&self) -> usize {
@@ -17,18 +32,24 @@ impl Node {
     pub fn compute_value(&self, indices: &[usize], values: &[f32]) -> f32 {
         let mut value = 0.0;
         for i in 0..indices.len() {
-            value += self.weights[indices[i]].value * values[i];
+            value += self.weights[indices[i]] * values[i];
         }
-        value + self.bias.value
+        value + self.bias
+    }
+
+    /// `compute_value` for `values` of every node of the previous layer in
+    /// order.
+    pub fn compute_dense_value(&self, values: &[f32]) -> f32 {
+        simd::dot(self.weights, values) + self.bias
     }
 
-    pub fn back_propagate(&mut self, delta: f32, prev_layer_status: &mut LayerStatus) {
+    pub fn back_propagate(&self, delta: f32, prev_layer_status: &mut LayerStatus) {
         for i in 0..prev_layer_status.active_nodes.len() {
             let id = prev_layer_status.active_nodes[i];
             let value = prev_layer_status.active_values[i];
-            prev_layer_status.deltas[i] += delta * self.weights[id].value;
-            self.weights[id].add_error(delta * value);
+            prev_layer_status.deltas[i] += delta * self.weights[id];
+            add_error(&self.weight_errors[id], delta * value);
         }
-        self.bias.add_error(delta);
+        add_error(self.bias_error, delta);
     }
 }
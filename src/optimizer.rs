@@ -0,0 +1,300 @@
+//! Update rules applied to the parameters after every batch.
+//!
+//! The errors accumulated by back-propagation are negative gradients, so the
+//! optimizers move the parameters along them.
+
+use std::io::{self, Read, Write};
+
+use crate::checkpoint::*;
+
+/// Number of state values every parameter keeps for its optimizer.
+pub const MAX_STATE_SIZE: usize = 2;
+
+pub trait Optimizer: Send + Sync {
+    /// Number of state values used per parameter, at most `MAX_STATE_SIZE`.
+    const STATE_SIZE: usize;
+    /// Identifies the optimizer in checkpoints, distinct for every optimizer.
+    const TAG: u8;
+
+    /// Called before the updates of every batch, `step` counting from 0.
+    fn begin_step(&mut self, step: usize);
+
+    /// Moves `value` along `error`, the accumulated negative gradient.
+    fn update(&self, value: &mut f32, error: f32, state: &mut [f32; MAX_STATE_SIZE]);
+
+    /// Catches `value` and `state` up on `steps` skipped updates with no
+    /// error, except for the moves the state would have made. Does nothing by
+    /// default.
+    fn catch_up(&self, _value: &mut f32, _state: &mut [f32; MAX_STATE_SIZE], _steps: usize) {}
+
+    /// Writes the hyperparameters.
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()>;
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Self>
+    where
+        Self: Sized;
+}
+
+/// Plain stochastic gradient descent.
+pub struct Sgd {
+    pub learning_rate: f32,
+}
+
+impl Sgd {
+    pub fn new(learning_rate: f32) -> Self {
+        Sgd { learning_rate }
+    }
+}
+
+impl Optimizer for Sgd {
+    const STATE_SIZE: usize = 0;
+    const TAG: u8 = 0;
+
+    fn begin_step(&mut self, _step: usize) {}
+
+    fn update(&self, value: &mut f32, error: f32, _state: &mut [f32; MAX_STATE_SIZE]) {
+        *value += self.learning_rate * error;
+    }
+
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_f32(w, self.learning_rate)
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        Ok(Sgd::new(read_f32(r)?))
+    }
+}
+
+/// SGD with heavy-ball momentum.
+pub struct Momentum {
+    pub learning_rate: f32,
+    pub momentum: f32,
+}
+
+impl Momentum {
+    pub fn new(learning_rate: f32) -> Self {
+        Momentum {
+            learning_rate,
+            momentum: 0.9,
+        }
+    }
+}
+
+impl Optimizer for Momentum {
+    const STATE_SIZE: usize = 1;
+    const TAG: u8 = 1;
+
+    fn begin_step(&mut self, _step: usize) {}
+
+    fn update(&self, value: &mut f32, error: f32, state: &mut [f32; MAX_STATE_SIZE]) {
+        state[0] = self.momentum * state[0] + error;
+        *value += self.learning_rate * state[0];
+    }
+
+    fn catch_up(&self, _value: &mut f32, state: &mut [f32; MAX_STATE_SIZE], steps: usize) {
+        state[0] *= self.momentum.powi(steps as i32);
+    }
+
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_f32(w, self.learning_rate)?;
+        write_f32(w, self.momentum)
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        Ok(Momentum {
+            learning_rate: read_f32(r)?,
+            momentum: read_f32(r)?,
+        })
+    }
+}
+
+pub struct Adagrad {
+    pub learning_rate: f32,
+    pub eps: f32,
+}
+
+impl Adagrad {
+    pub fn new(learning_rate: f32) -> Self {
+        Adagrad {
+            learning_rate,
+            eps: 1e-8,
+        }
+    }
+}
+
+impl Optimizer for Adagrad {
+    const STATE_SIZE: usize = 1;
+    const TAG: u8 = 2;
+
+    fn begin_step(&mut self, _step: usize) {}
+
+    fn update(&self, value: &mut f32, error: f32, state: &mut [f32; MAX_STATE_SIZE]) {
+        state[0] += error * error;
+        *value += self.learning_rate * error / (state[0].sqrt() + self.eps);
+    }
+
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_f32(w, self.learning_rate)?;
+        write_f32(w, self.eps)
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        Ok(Adagrad {
+            learning_rate: read_f32(r)?,
+            eps: read_f32(r)?,
+        })
+    }
+}
+
+/// Adam with bias correction folded into the learning rate of each step.
+pub struct Adam {
+    pub learning_rate: f32,
+    pub beta1: f32,
+    pub beta2: f32,
+    pub eps: f32,
+    // The bias-corrected learning rate of the current step.
+    rate: f32,
+}
+
+impl Adam {
+    pub fn new(learning_rate: f32) -> Self {
+        Adam::with_betas(learning_rate, 0.9, 0.999)
+    }
+
+    pub fn with_betas(learning_rate: f32, beta1: f32, beta2: f32) -> Self {
+        Adam {
+            learning_rate,
+            beta1,
+            beta2,
+            eps: 1e-8,
+            rate: learning_rate,
+        }
+    }
+}
+
+impl Optimizer for Adam {
+    const STATE_SIZE: usize = 2;
+    const TAG: u8 = 3;
+
+    fn begin_step(&mut self, step: usize) {
+        let t = step as i32 + 1;
+        self.rate =
+            self.learning_rate * (1.0 - self.beta2.powi(t)).sqrt() / (1.0 - self.beta1.powi(t));
+    }
+
+    fn update(&self, value: &mut f32, error: f32, state: &mut [f32; MAX_STATE_SIZE]) {
+        let [mom, vel] = state;
+        *mom = self.beta1 * *mom + (1.0 - self.beta1) * error;
+        *vel = self.beta2 * *vel + (1.0 - self.beta2) * error.powi(2);
+        *value += self.rate * *mom / (vel.sqrt() + self.eps);
+    }
+
+    fn catch_up(&self, _value: &mut f32, state: &mut [f32; MAX_STATE_SIZE], steps: usize) {
+        state[0] *= self.beta1.powi(steps as i32);
+        state[1] *= self.beta2.powi(steps as i32);
+    }
+
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_f32(w, self.learning_rate)?;
+        write_f32(w, self.beta1)?;
+        write_f32(w, self.beta2)?;
+        write_f32(w, self.eps)
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut adam = Adam::with_betas(read_f32(r)?, read_f32(r)?, read_f32(r)?);
+        adam.eps = read_f32(r)?;
+        Ok(adam)
+    }
+}
+
+/// Adam with weight decay decoupled from the gradient.
+pub struct AdamW {
+    pub adam: Adam,
+    pub weight_decay: f32,
+}
+
+impl AdamW {
+    pub fn new(learning_rate: f32, weight_decay: f32) -> Self {
+        AdamW {
+            adam: Adam::new(learning_rate),
+            weight_decay,
+        }
+    }
+}
+
+impl Optimizer for AdamW {
+    const STATE_SIZE: usize = 2;
+    const TAG: u8 = 4;
+
+    fn begin_step(&mut self, step: usize) {
+        self.adam.begin_step(step);
+    }
+
+    fn update(&self, value: &mut f32, error: f32, state: &mut [f32; MAX_STATE_SIZE]) {
+        *value -= self.adam.learning_rate * self.weight_decay * *value;
+        self.adam.update(value, error, state);
+    }
+
+    fn catch_up(&self, value: &mut f32, state: &mut [f32; MAX_STATE_SIZE], steps: usize) {
+        *value *= (1.0 - self.adam.learning_rate * self.weight_decay).powi(steps as i32);
+        self.adam.catch_up(value, state, steps);
+    }
+
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.adam.write_to(w)?;
+        write_f32(w, self.weight_decay)
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        Ok(AdamW {
+            adam: Adam::read_from(r)?,
+            weight_decay: read_f32(r)?,
+        })
+    }
+}
+
+#[test]
+fn test() {
+    // Minimizes (x - 3)^2 from x = 0.
+    fn minimize<O: Optimizer>(mut optimizer: O, steps: usize) -> f32 {
+        let mut x = 0.0;
+        let mut state = [0.0; MAX_STATE_SIZE];
+        for step in 0..steps {
+            optimizer.begin_step(step);
+            let error = -2.0 * (x - 3.0);
+            optimizer.update(&mut x, error, &mut state);
+        }
+        x
+    }
+    assert!((minimize(Sgd::new(0.1), 100) - 3.0).abs() < 1e-3);
+    assert!((minimize(Momentum::new(0.01), 500) - 3.0).abs() < 1e-3);
+    assert!((minimize(Adagrad::new(1.0), 500) - 3.0).abs() < 1e-3);
+    assert!((minimize(Adam::new(0.1), 1000) - 3.0).abs() < 1e-2);
+    // The decay pulls the minimum towards 0.
+    let x = minimize(AdamW::new(0.1, 0.1), 1000);
+    assert!(x < 2.99 && x > 2.0);
+
+    // Bias correction makes the first Adam step the learning rate.
+    let mut adam = Adam::new(0.1);
+    adam.begin_step(0);
+    let mut x = 0.0;
+    adam.update(&mut x, 5.0, &mut [0.0; MAX_STATE_SIZE]);
+    assert!((x - 0.1).abs() < 1e-6);
+
+    // Catching up equals updating with no error, but for the value.
+    let mut state = [0.0; MAX_STATE_SIZE];
+    adam.update(&mut x, 1.0, &mut state);
+    let mut updated = state;
+    for _ in 0..5 {
+        adam.update(&mut x, 0.0, &mut updated);
+    }
+    adam.catch_up(&mut x, &mut state, 5);
+    assert!((state[0] - updated[0]).abs() < 1e-6);
+    assert!((state[1] - updated[1]).abs() < 1e-6);
+
+    let mut bytes = Vec::new();
+    AdamW::new(0.5, 0.25).write_to(&mut bytes).unwrap();
+    let adamw = AdamW::read_from(&mut bytes.as_slice()).unwrap();
+    assert_eq!((adamw.adam.learning_rate, adamw.weight_decay), (0.5, 0.25));
+}
@@ -1,26 +1,142 @@
-use crate::adam::Adam;
+use std::{
+    io::{self, Read, Write},
+    sync::atomic::{AtomicU32, Ordering},
+};
 
-pub struct Param {
-    pub value: f32,
-    error: f32,
-    adam: Adam,
+use rayon::prelude::*;
+
+use crate::{
+    checkpoint::*,
+    optimizer::{Optimizer, MAX_STATE_SIZE},
+};
+
+/// Parameters stored as a structure of arrays: their values, the errors
+/// accumulated for the next update and the optimizer states.
+pub struct Params {
+    values: Vec<f32>,
+    // f32 bits, so that errors can be added from several threads at once.
+    errors: Vec<AtomicU32>,
+    states: Vec<[f32; MAX_STATE_SIZE]>,
 }
 
-impl Param {
-    pub fn new(value: f32) -> Self {
-        Param {
-            value,
-            error: 0.0,
-            adam: Adam::default(),
+impl Params {
+    pub fn new(values: Vec<f32>) -> Self {
+        let len = values.len();
+        Params {
+            values,
+            errors: (0..len).map(|_| AtomicU32::new(0.0f32.to_bits())).collect(),
+            states: vec![[0.0; MAX_STATE_SIZE]; len],
         }
     }
 
-    pub fn add_error(&mut self, value: f32) {
-        self.error += value;
+    pub fn len(&self) -> usize {
+        self.values.len()
     }
 
-    pub fn update(&mut self, rate: f32) {
-        self.value += rate * self.adam.apply(self.error);
-        self.error = 0.0;
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
     }
+
+    pub fn values(&self) -> &[f32] {
+        &self.values
+    }
+
+    pub fn values_mut(&mut self) -> &mut [f32] {
+        &mut self.values
+    }
+
+    pub fn errors(&self) -> &[AtomicU32] {
+        &self.errors
+    }
+
+    pub fn error(&self, i: usize) -> f32 {
+        f32::from_bits(self.errors[i].load(Ordering::Relaxed))
+    }
+
+    /// Splits the parameters into consecutive mutable chunks of `size`, to be
+    /// updated in parallel.
+    pub fn par_chunks_mut(
+        &mut self,
+        size: usize,
+    ) -> impl IndexedParallelIterator<Item = ParamsMut<'_>> + '_ {
+        self.values
+            .par_chunks_mut(size)
+            .zip(self.errors.par_chunks_mut(size))
+            .zip(self.states.par_chunks_mut(size))
+            .map(|((values, errors), states)| ParamsMut {
+                values,
+                errors,
+                states,
+            })
+    }
+
+    /// Writes the value of the `i`th parameter and its first `state_size`
+    /// optimizer state values. Pending errors are not saved.
+    pub fn write_param<W: Write>(&self, w: &mut W, i: usize, state_size: usize) -> io::Result<()> {
+        write_f32(w, self.values[i])?;
+        for &state in &self.states[i][..state_size] {
+            write_f32(w, state)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a parameter written by `write_param` and appends it.
+    pub fn read_param<R: Read>(&mut self, r: &mut R, state_size: usize) -> io::Result<()> {
+        self.values.push(read_f32(r)?);
+        self.errors.push(AtomicU32::new(0.0f32.to_bits()));
+        let mut state = [0.0; MAX_STATE_SIZE];
+        for state in &mut state[..state_size] {
+            *state = read_f32(r)?;
+        }
+        self.states.push(state);
+        Ok(())
+    }
+}
+
+/// Adds `value` to an error atomically, retrying with a compare-exchange
+/// until no other thread has added in between, so that no addition is lost.
+pub fn add_error(error: &AtomicU32, value: f32) {
+    let _ = error.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+        Some((f32::from_bits(bits) + value).to_bits())
+    });
+}
+
+/// A mutable chunk of `Params`.
+pub struct ParamsMut<'a> {
+    values: &'a mut [f32],
+    errors: &'a mut [AtomicU32],
+    states: &'a mut [[f32; MAX_STATE_SIZE]],
+}
+
+impl ParamsMut<'_> {
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Applies the error of the `i`th parameter and resets it.
+    pub fn update<O: Optimizer>(&mut self, i: usize, optimizer: &O) {
+        let error = f32::from_bits(*self.errors[i].get_mut());
+        optimizer.update(&mut self.values[i], error, &mut self.states[i]);
+        *self.errors[i].get_mut() = 0.0f32.to_bits();
+    }
+
+    /// Applies `steps` updates skipped for lack of errors to the `i`th
+    /// parameter, see `Optimizer::catch_up`.
+    pub fn catch_up<O: Optimizer>(&mut self, i: usize, optimizer: &O, steps: usize) {
+        optimizer.catch_up(&mut self.values[i], &mut self.states[i], steps);
+    }
+}
+
+#[test]
+fn test() {
+    // Sums of small integers are exact whatever the order of the additions.
+    let params = Params::new(vec![0.0; 1]);
+    (0..40000)
+        .into_par_iter()
+        .for_each(|_| add_error(&params.errors()[0], 1.0));
+    assert_eq!(params.error(0), 40000.0);
 }
@@ -0,0 +1,160 @@
+//! Kernels using AVX2 and FMA when the CPU supports them, falling back to the
+//! `scalar` versions otherwise. Both agree up to floating point rounding.
+//!
+//! Sparse dot products over the inputs are left scalar: bound by the latency
+//! of their random loads, they run no faster gathered into vectors.
+
+/// Sum of `a[i] * b[i]`.
+pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+    let len = a.len().min(b.len());
+    #[cfg(target_arch = "x86_64")]
+    {
+        if has_avx2() {
+            return unsafe { avx2::dot(&a[..len], &b[..len]) };
+        }
+    }
+    scalar::dot(&a[..len], &b[..len])
+}
+
+/// The position in `indices` of the first maximum of `data[indices[i]]`, 0
+/// if `indices` is empty. NaNs are not supported.
+pub fn gather_argmax(data: &[f32], indices: &[usize]) -> usize {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if has_avx2() {
+            return unsafe { avx2::gather_argmax(data, indices) };
+        }
+    }
+    scalar::gather_argmax(data, indices)
+}
+
+#[cfg(target_arch = "x86_64")]
+fn has_avx2() -> bool {
+    is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma")
+}
+
+pub mod scalar {
+    pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+        a.iter().zip(b).map(|(a, b)| a * b).sum()
+    }
+
+    pub fn gather_argmax(data: &[f32], indices: &[usize]) -> usize {
+        let mut best = 0;
+        for j in 1..indices.len() {
+            if data[indices[j]] > data[indices[best]] {
+                best = j;
+            }
+        }
+        best
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod avx2 {
+    use std::arch::x86_64::*;
+
+    /// Loads `data[indices[i..i + 8]]`. Bounds-checked scalar loads turn out
+    /// faster than `_mm256_i64gather_ps`, and a macro than an `#[inline]`
+    /// function.
+    macro_rules! gather8 {
+        ($data:expr, $indices:expr, $i:expr) => {{
+            let j = &$indices[$i..$i + 8];
+            let data = $data;
+            _mm256_set_ps(
+                data[j[7]], data[j[6]], data[j[5]], data[j[4]], data[j[3]], data[j[2]], data[j[1]],
+                data[j[0]],
+            )
+        }};
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn dot(a: &[f32], b: &[f32]) -> f32 {
+        let len = a.len();
+        let (a, b) = (a.as_ptr(), b.as_ptr());
+        let mut sum0 = _mm256_setzero_ps();
+        let mut sum1 = _mm256_setzero_ps();
+        let mut i = 0;
+        while i + 16 <= len {
+            sum0 = _mm256_fmadd_ps(_mm256_loadu_ps(a.add(i)), _mm256_loadu_ps(b.add(i)), sum0);
+            sum1 = _mm256_fmadd_ps(
+                _mm256_loadu_ps(a.add(i + 8)),
+                _mm256_loadu_ps(b.add(i + 8)),
+                sum1,
+            );
+            i += 16;
+        }
+        if i + 8 <= len {
+            sum0 = _mm256_fmadd_ps(_mm256_loadu_ps(a.add(i)), _mm256_loadu_ps(b.add(i)), sum0);
+            i += 8;
+        }
+        let mut sum = horizontal_sum(_mm256_add_ps(sum0, sum1));
+        while i < len {
+            sum += *a.add(i) * *b.add(i);
+            i += 1;
+        }
+        sum
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn gather_argmax(data: &[f32], indices: &[usize]) -> usize {
+        let len = indices.len();
+        let mut best = 0;
+        let mut best_value = f32::NEG_INFINITY;
+        let mut i = 0;
+        while i + 8 <= len {
+            let gathered = gather8!(data, indices, i);
+            let mut max = _mm256_max_ps(gathered, _mm256_permute2f128_ps(gathered, gathered, 1));
+            max = _mm256_max_ps(max, _mm256_shuffle_ps(max, max, 0b01_00_11_10));
+            max = _mm256_max_ps(max, _mm256_shuffle_ps(max, max, 0b10_11_00_01));
+            let max_value = _mm256_cvtss_f32(max);
+            if i == 0 || max_value > best_value {
+                let mask = _mm256_movemask_ps(_mm256_cmp_ps(gathered, max, _CMP_EQ_OQ));
+                best = i + mask.trailing_zeros() as usize;
+                best_value = max_value;
+            }
+            i += 8;
+        }
+        while i < len {
+            let value = data[indices[i]];
+            if i == 0 || value > best_value {
+                best = i;
+                best_value = value;
+            }
+            i += 1;
+        }
+        best
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx2,fma")]
+    unsafe fn horizontal_sum(v: __m256) -> f32 {
+        let sum = _mm_add_ps(_mm256_castps256_ps128(v), _mm256_extractf128_ps(v, 1));
+        let sum = _mm_add_ps(sum, _mm_movehl_ps(sum, sum));
+        let sum = _mm_add_ss(sum, _mm_shuffle_ps(sum, sum, 1));
+        _mm_cvtss_f32(sum)
+    }
+}
+
+#[test]
+fn test() {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let data: Vec<f32> = (0..1000).map(|_| rng.gen_range(-1.0..1.0)).collect();
+    for &len in &[0, 1, 7, 8, 9, 16, 31, 100] {
+        let values: Vec<f32> = (0..len).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let indices: Vec<usize> = (0..len).map(|_| rng.gen_range(0..data.len())).collect();
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-4;
+        assert!(close(
+            dot(&data[..len], &values),
+            scalar::dot(&data[..len], &values)
+        ));
+        assert_eq!(
+            gather_argmax(&data, &indices),
+            scalar::gather_argmax(&data, &indices)
+        );
+    }
+    // Ties go to the first maximum.
+    assert_eq!(gather_argmax(&[0.0, 1.0], &[0, 1, 0, 1, 1, 0, 1, 0, 1]), 1);
+    assert_eq!(gather_argmax(&[0.0, 1.0], &[0, 0, 0, 0, 0, 0, 0, 0, 1]), 8);
+}
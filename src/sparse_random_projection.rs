@@ -1,3 +1,175 @@
+use std::io::{self, Read, Write};
+
+use rand::rngs::StdRng;
+
+use crate::{
+    checkpoint::*,
+    hasher::{pack_hashes, Hasher, HasherConfig, Probe},
+};
+
+/// Each projection looks at one in `RATIO` input dimensions.
+const RATIO: usize = 3;
+
+/// Signed sparse random projection (SimHash).
+///
+/// Every hash is the sign bit of the dot product between the input and a
+/// random `{-1, +1}` vector that is nonzero on a random `1 / RATIO` of the
+/// dimensions, so colliding inputs have a small angle between them.
 pub struct SparseRandomProjection {
+    size: usize,
+    // Inverted by input dimension: the projections of dimension `d` are
+    // `hash_ids[offsets[d]..offsets[d + 1]]` with the matching `signs`.
+    offsets: Vec<usize>,
+    hash_ids: Vec<u32>,
+    signs: Vec<i8>,
+}
+
+impl Hasher for SparseRandomProjection {
+    const TAG: u8 = 3;
+
+    fn new(size: usize, number_of_bits: usize, _config: &HasherConfig, rng: &mut StdRng) -> Self {
+        use rand::{seq::index::sample, Rng};
+
+        let sample_size = (number_of_bits + RATIO - 1) / RATIO;
+        let mut samples = Vec::with_capacity(size * sample_size);
+        let mut counts = vec![0; number_of_bits + 1];
+        for i in 0..size {
+            for d in sample(rng, number_of_bits, sample_size) {
+                let sign = if rng.gen::<bool>() { 1 } else { -1 };
+                samples.push((d, i as u32, sign));
+                counts[d + 1] += 1;
+            }
+        }
+
+        let mut offsets = counts;
+        for d in 0..number_of_bits {
+            offsets[d + 1] += offsets[d];
+        }
+        let mut next = offsets.clone();
+        let mut hash_ids = vec![0; samples.len()];
+        let mut signs = vec![0; samples.len()];
+        for (d, i, sign) in samples {
+            hash_ids[next[d]] = i;
+            signs[next[d]] = sign;
+            next[d] += 1;
+        }
+
+        SparseRandomProjection {
+            size,
+            offsets,
+            hash_ids,
+            signs,
+        }
+    }
+
+    fn hash(&self, weights: &[f32]) -> Vec<usize> {
+        let mut sums = vec![0.0; self.size];
+        for (d, &weight) in weights.iter().enumerate() {
+            self.project(&mut sums, d, weight);
+        }
+        Self::sign_bits(&sums)
+    }
+
+    fn hash_sparse(&self, weights: &[f32], indices: &[usize]) -> Vec<usize> {
+        let mut sums = vec![0.0; self.size];
+        for (&d, &weight) in indices.iter().zip(weights) {
+            self.project(&mut sums, d, weight);
+        }
+        Self::sign_bits(&sums)
+    }
+
+    fn probe_sparse(&self, weights: &[f32], indices: &[usize]) -> Vec<Probe> {
+        let mut sums = vec![0.0; self.size];
+        for (&d, &weight) in indices.iter().zip(weights) {
+            self.project(&mut sums, d, weight);
+        }
+        Self::sign_bits(&sums)
+            .into_iter()
+            .zip(sums)
+            .map(|(hash, sum)| Probe {
+                hash,
+                runner_up: 1 - hash,
+                margin: sum.abs(),
+            })
+            .collect()
+    }
+
+    fn hashes_to_indices(
+        &self,
+        hashes: &[usize],
+        k: usize,
+        l: usize,
+        range_pow: usize,
+    ) -> Vec<usize> {
+        pack_hashes(hashes, k, l, range_pow, 2)
+    }
+
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_usize(w, self.size)?;
+        write_usizes(w, &self.offsets)?;
+        write_u32s(w, &self.hash_ids)?;
+        self.signs.iter().try_for_each(|&s| write_u8(w, s as u8))
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        let size = read_usize(r)?;
+        let offsets = read_usizes(r)?;
+        let hash_ids = read_u32s(r)?;
+        let signs = (0..hash_ids.len())
+            .map(|_| read_u8(r).map(|s| s as i8))
+            .collect::<io::Result<Vec<_>>>()?;
+        if offsets.last() != Some(&hash_ids.len())
+            || offsets.windows(2).any(|o| o[0] > o[1])
+            || hash_ids.iter().any(|&i| i as usize >= size)
+        {
+            return Err(invalid_data("corrupted hasher"));
+        }
+        Ok(SparseRandomProjection {
+            size,
+            offsets,
+            hash_ids,
+            signs,
+        })
+    }
+}
+
+impl SparseRandomProjection {
+    fn project(&self, sums: &mut [f32], d: usize, weight: f32) {
+        if weight == 0.0 {
+            return;
+        }
+        for j in self.offsets[d]..self.offsets[d + 1] {
+            sums[self.hash_ids[j] as usize] += self.signs[j] as f32 * weight;
+        }
+    }
+
+    fn sign_bits(sums: &[f32]) -> Vec<usize> {
+        sums.iter().map(|&s| if s >= 0.0 { 0 } else { 1 }).collect()
+    }
+}
+
+#[test]
+fn test() {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let hash = SparseRandomProjection::new(16, 30, &HasherConfig::default(), &mut rng);
+    let weights: Vec<f32> = (0..30).map(|i| (i as f32 * 0.7).sin() + 0.1).collect();
+    let indices: Vec<usize> = (0..30).collect();
+    let hashes = hash.hash(&weights);
+    assert_eq!(hashes, hash.hash_sparse(&weights, &indices));
+
+    // Flipping the input flips every sign bit.
+    let negated: Vec<f32> = weights.iter().map(|w| -w).collect();
+    let flipped: Vec<usize> = hashes.iter().map(|h| 1 - h).collect();
+    assert_eq!(hash.hash(&negated), flipped);
+
+    assert_eq!(
+        hash.hash(&[0.0, 0.0, 0.5, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, -0.3]),
+        hash.hash_sparse(&[0.5, -0.3], &[2, 10])
+    );
 
+    // The sign bits of every table, least significant first, cut to 3 bits.
+    let bits = [1, 0, 1, 1, 0, 0, 0, 1, 1, 1, 1, 1, 0, 1, 0, 0];
+    assert_eq!(hash.hashes_to_indices(&bits, 4, 4, 3), vec![5, 0, 7, 2]);
 }
@@ -1,89 +1,167 @@
-use crate::hasher::Hasher;
+use std::io::{self, Read, Write};
 
-const BIN_SIZE: usize = 8;
+use rand::rngs::StdRng;
+
+use crate::{
+    checkpoint::*,
+    hasher::{bin_probe, pack_hashes, Hasher, HasherConfig, Probe},
+    simd,
+};
 
 pub struct WtaHash {
     size: usize,
+    number_of_bits: usize,
+    bin_size: usize,
     indices: Vec<usize>,
+    inverse_index: InverseIndex,
 }
 
 impl Hasher for WtaHash {
-    fn new(size: usize, number_of_bits: usize) -> Self {
-        use rand::seq::SliceRandom;
+    const TAG: u8 = 0;
 
-        assert!(BIN_SIZE <= number_of_bits);
+    fn new(size: usize, number_of_bits: usize, config: &HasherConfig, rng: &mut StdRng) -> Self {
+        use rand::seq::SliceRandom;
 
-        let mut rng = rand::thread_rng();
+        let bin_size = config.bin_size;
+        assert!(0 < bin_size && bin_size <= number_of_bits);
 
         let mut n_array: Vec<usize> = (0..number_of_bits).collect();
-        let mut indices = vec![0; size * BIN_SIZE];
+        let mut indices = vec![0; size * bin_size];
 
         for i in 0..size {
-            n_array.shuffle(&mut rng);
-            for j in 0..BIN_SIZE {
-                indices[i * BIN_SIZE + j] = n_array[j];
+            n_array.shuffle(rng);
+            for j in 0..bin_size {
+                indices[i * bin_size + j] = n_array[j];
             }
         }
 
-        WtaHash { size, indices }
+        WtaHash::from_indices(size, number_of_bits, bin_size, indices)
     }
 
     fn hash(&self, weights: &[f32]) -> Vec<usize> {
         // binsize is the number of times the range is larger than the total number of hashes we need.
-        let mut hashes = vec![0; self.size];
-
-        for i in 0..self.size {
-            let mut w = f32::MIN;
-            for j in 0..BIN_SIZE {
-                let k = self.indices[i * BIN_SIZE + j];
-                if w < weights[k] {
-                    w = weights[k];
-                    hashes[i] = j;
-                }
-            }
-        }
+        let hashes: Vec<_> = self
+            .indices
+            .chunks(self.bin_size)
+            .map(|bin| simd::gather_argmax(weights, bin))
+            .collect();
         hashes
     }
 
     fn hash_sparse(&self, weights: &[f32], indices: &[usize]) -> Vec<usize> {
-        let mut hashes = vec![0; self.size];
-
-        for i in 0..self.size {
-            let mut w = f32::MIN;
-            for j in 0..BIN_SIZE {
-                let k = self.indices[i * BIN_SIZE + j];
-                let weight = indices
-                    .iter()
-                    .position(|i| k == *i)
-                    .map(|i| weights[i])
-                    .unwrap_or_default();
-                if w < weight {
-                    w = weight;
-                    hashes[i] = j;
-                }
-            }
-        }
-        hashes
+        let values = self.inverse_index.scatter(weights, indices);
+        values
+            .chunks(self.bin_size)
+            .map(|bin| (1..bin.len()).fold(0, |best, j| if bin[j] > bin[best] { j } else { best }))
+            .collect()
     }
 
-    fn hashes_to_indices(hashes: &[usize], k: usize, l: usize, range_pow: usize) -> Vec<usize> {
-        let bin_size_log2 = (BIN_SIZE as f32).log2() as usize;
-        (0..l)
-            .map(|i| {
-                let mut index = 0;
-                for j in 0..k {
-                    let h = hashes[k * i + j];
-                    index |= h << (bin_size_log2 * j);
-                }
-                index & ((1 << range_pow) - 1)
-            })
+    fn probe_sparse(&self, weights: &[f32], indices: &[usize]) -> Vec<Probe> {
+        let values = self.inverse_index.scatter(weights, indices);
+        values
+            .chunks(self.bin_size)
+            .map(|bin| bin_probe(bin.iter().cloned()))
             .collect()
     }
+
+    fn hashes_to_indices(
+        &self,
+        hashes: &[usize],
+        k: usize,
+        l: usize,
+        range_pow: usize,
+    ) -> Vec<usize> {
+        pack_hashes(hashes, k, l, range_pow, self.bin_size)
+    }
+
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_usize(w, self.size)?;
+        write_usize(w, self.number_of_bits)?;
+        write_usize(w, self.bin_size)?;
+        write_usizes(w, &self.indices)
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        let size = read_usize(r)?;
+        let number_of_bits = read_usize(r)?;
+        let bin_size = read_usize(r)?;
+        let indices = read_usizes(r)?;
+        if bin_size == 0
+            || indices.len() != size * bin_size
+            || indices.iter().any(|&i| i >= number_of_bits)
+        {
+            return Err(invalid_data("corrupted hasher"));
+        }
+        Ok(WtaHash::from_indices(
+            size,
+            number_of_bits,
+            bin_size,
+            indices,
+        ))
+    }
+}
+
+impl WtaHash {
+    fn from_indices(
+        size: usize,
+        number_of_bits: usize,
+        bin_size: usize,
+        indices: Vec<usize>,
+    ) -> Self {
+        WtaHash {
+            size,
+            number_of_bits,
+            bin_size,
+            inverse_index: InverseIndex::new(&indices, number_of_bits),
+            indices,
+        }
+    }
+}
+
+/// The slots of the bins every input is drawn into, so that sparse inputs
+/// are hashed in time linear in their number of nonzeros and of slots.
+pub(crate) struct InverseIndex {
+    // The slots of input `i` are `slots[offsets[i]..offsets[i + 1]]`.
+    offsets: Vec<usize>,
+    slots: Vec<u32>,
+}
+
+impl InverseIndex {
+    pub(crate) fn new(indices: &[usize], number_of_bits: usize) -> Self {
+        let mut offsets = vec![0; number_of_bits + 1];
+        for &i in indices {
+            offsets[i + 1] += 1;
+        }
+        for i in 0..number_of_bits {
+            offsets[i + 1] += offsets[i];
+        }
+        let mut next = offsets.clone();
+        let mut slots = vec![0; indices.len()];
+        for (slot, &i) in indices.iter().enumerate() {
+            slots[next[i]] = slot as u32;
+            next[i] += 1;
+        }
+        InverseIndex { offsets, slots }
+    }
+
+    /// The value of every slot for the sparse input, 0 for absent inputs.
+    pub(crate) fn scatter(&self, weights: &[f32], indices: &[usize]) -> Vec<f32> {
+        let mut values = vec![0.0; self.slots.len()];
+        for (&i, &weight) in indices.iter().zip(weights) {
+            for &slot in &self.slots[self.offsets[i]..self.offsets[i + 1]] {
+                values[slot as usize] = weight;
+            }
+        }
+        values
+    }
 }
 
 #[test]
 fn test() {
-    let hash = WtaHash::new(4, 8);
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let hash = WtaHash::new(4, 8, &HasherConfig::default(), &mut rng);
     assert_eq!(
         hash.hash(&[0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]),
         hash.hash_sparse(&[], &[])
@@ -107,12 +185,59 @@ fn test() {
 
     let hashes = hash.hash(&[0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
     dbg!(&hashes);
-    dbg!(WtaHash::hashes_to_indices(&hashes, 2, 2, 10));
+    dbg!(hash.hashes_to_indices(&hashes, 2, 2, 10));
     let hashes = hash.hash(&[0.0, 0.5, 0.0, 0.4, 0.0, 0.3, 0.0, 0.2]);
     dbg!(&hashes);
-    dbg!(WtaHash::hashes_to_indices(&hashes, 2, 2, 10));
+    dbg!(hash.hashes_to_indices(&hashes, 2, 2, 10));
+
+    // Sparse inputs hash like their dense counterparts, negative values and
+    // ties included.
+    let hash = WtaHash::new(64, 40, &HasherConfig::default(), &mut rng);
+    for _ in 0..100 {
+        let mut dense = vec![0.0; 40];
+        let mut indices = Vec::new();
+        let mut weights = Vec::new();
+        for (i, value) in dense.iter_mut().enumerate() {
+            if rng.gen_bool(0.2) {
+                *value = rng.gen_range(-2..3) as f32;
+                indices.push(i);
+                weights.push(*value);
+            }
+        }
+        assert_eq!(hash.hash(&dense), hash.hash_sparse(&weights, &indices));
+    }
 
-    let hash = WtaHash::new(100, 50);
+    let hash = WtaHash::new(100, 50, &HasherConfig::default(), &mut rng);
     let hashes = hash.hash_sparse(&[1.0], &[0]);
     dbg!(&hashes);
+
+    // Bins of 3 pack two hashes into 9 distinct indices, and the probes
+    // agree with the hashes.
+    let config = HasherConfig {
+        bin_size: 3,
+        ..HasherConfig::default()
+    };
+    let hash = WtaHash::new(2, 5, &config, &mut rng);
+    let mut indices = Vec::new();
+    for h0 in 0..3 {
+        for h1 in 0..3 {
+            indices.extend(hash.hashes_to_indices(&[h0, h1], 2, 1, 4));
+        }
+    }
+    assert_eq!(indices, [0, 3, 6, 1, 4, 7, 2, 5, 8]);
+    for _ in 0..100 {
+        let indices: Vec<usize> = (0..5).filter(|_| rng.gen_bool(0.5)).collect();
+        let weights: Vec<f32> = indices
+            .iter()
+            .map(|_| rng.gen_range(-2..3) as f32)
+            .collect();
+        let probes = hash.probe_sparse(&weights, &indices);
+        let hashes = hash.hash_sparse(&weights, &indices);
+        assert_eq!(probes.iter().map(|p| p.hash).collect::<Vec<_>>(), hashes);
+        assert!(probes.iter().all(|p| p.runner_up < 3 && p.margin >= 0.0));
+    }
+    let value = |i: usize| hash.indices[i] as f32;
+    let weights: Vec<f32> = (0..5).map(|i| i as f32).collect();
+    let probe = hash.probe_sparse(&weights, &[0, 1, 2, 3, 4])[0];
+    assert_eq!(probe.margin, value(probe.hash) - value(probe.runner_up));
 }